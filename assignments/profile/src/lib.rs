@@ -1,5 +0,0 @@
-pub mod rumdis;
-pub mod rumio;
-pub mod rumload;
-pub mod rummem;
-pub mod rumrun;
\ No newline at end of file
@@ -0,0 +1,69 @@
+//! Benchmarks for LoadProgram from a segment other than segment 0.
+//!
+//! Run with `cargo bench --bench load_program`. Each backend is measured
+//! loading a segment that is never modified afterwards, which both now
+//! share with segment 0, against the full copy LoadProgram used to make.
+
+use std::{fs::File, io};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rum::{
+    rumio::Streams,
+    rumload,
+    rummem::{BTreeMemory, FreeListMemory, SegmentStore},
+    rumrun::Machine,
+};
+
+/// Length of the segment loaded as the program, about the size of sandmark.
+const SEGMENT_WORDS: u32 = 1 << 16;
+
+/// Instructions of sandmark run per iteration, including its one LoadProgram copy.
+const SANDMARK_STEPS: u64 = 20_000_000;
+
+fn load_segment<M: SegmentStore>(c: &mut Criterion, name: &str) {
+    let mut memory = M::default();
+    let index = memory.map(SEGMENT_WORDS).unwrap();
+
+    let mut group = c.benchmark_group("load_segment");
+    group.bench_function(BenchmarkId::new("shared", name), |b| {
+        b.iter(|| {
+            memory.load_segment(black_box(index)).unwrap();
+            black_box(memory.get_program_length())
+        })
+    });
+    group.bench_function(BenchmarkId::new("copied", name), |b| {
+        b.iter(|| {
+            let program = memory.segment(black_box(index)).unwrap().to_vec();
+            memory.load_program(program);
+            black_box(memory.get_program_length())
+        })
+    });
+    group.finish();
+}
+
+fn sandmark<M: SegmentStore>(c: &mut Criterion, name: &str) {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/rum-binaries/sandmark.umz");
+    let program = rumload::load(File::open(path).unwrap()).unwrap();
+
+    let mut group = c.benchmark_group("sandmark");
+    group.sample_size(10);
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            let mut memory = M::default();
+            memory.load_program(program.clone());
+            let mut machine = Machine::with_io(memory, Streams::new(io::empty(), io::sink()));
+            machine.run_for(SANDMARK_STEPS).unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    load_segment::<FreeListMemory>(c, "freelist");
+    load_segment::<BTreeMemory>(c, "btree");
+    sandmark::<FreeListMemory>(c, "freelist");
+    sandmark::<BTreeMemory>(c, "btree");
+}
+
+criterion_group!(load_program, benches);
+criterion_main!(load_program);
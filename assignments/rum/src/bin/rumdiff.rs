@@ -0,0 +1,172 @@
+use std::{env, fs, process::exit};
+
+use rand::{rngs::StdRng, SeedableRng};
+use rum::{
+    rumdiff::{self, Agreement, Divergence, Lockstep},
+    rumgen, rumload,
+    rummem::{BTreeMemory, FreeListMemory},
+    rumrun::Outcome,
+};
+
+const USAGE: &str = "\
+Usage: rumdiff [options] [program...]
+Run each program with B-tree and checked free-list memory in lockstep,
+comparing the two machines after every instruction.
+Options:
+  --engines               instead compare the plain and fused engines (both
+                          with free-list memory) every few instructions
+  --input <file>          give each program the contents of <file> as input
+  --max-steps <n>         compare at most n instructions of each program
+  --random <n>            also compare n randomly generated programs
+  --seed <n>              seed for the random programs (default: random)";
+
+/// Blocks of instructions in each random program (see `rumgen::program`).
+const RANDOM_BLOCKS: usize = 256;
+
+/// Instructions between comparisons of the plain and fused engines.
+const ENGINE_INTERVAL: u64 = 1000;
+
+/// Command-line options.
+#[derive(Default)]
+struct Options {
+    programs: Vec<String>,
+    engines: bool,
+    input: Vec<u8>,
+    max_steps: Option<u64>,
+    random: u64,
+    seed: Option<u64>,
+}
+
+impl Options {
+    /// Parse the command line, printing usage and exiting if it is malformed.
+    fn parse() -> Options {
+        let mut options = Options::default();
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--input" => {
+                    let path = value(&mut args);
+                    options.input = fs::read(&path).unwrap_or_else(|e| {
+                        eprintln!("could not read {}: {}", path, e);
+                        exit(1);
+                    })
+                }
+                "--engines" => options.engines = true,
+                "--max-steps" => options.max_steps = Some(number(&mut args)),
+                "--random" => options.random = number(&mut args),
+                "--seed" => options.seed = Some(number(&mut args)),
+                _ if !arg.starts_with("--") => options.programs.push(arg),
+                _ => usage(),
+            }
+        }
+        if options.programs.is_empty() && options.random == 0 {
+            usage();
+        }
+        options
+    }
+}
+
+/// Get the value following a flag.
+fn value(args: &mut impl Iterator<Item = String>) -> String {
+    args.next().unwrap_or_else(|| usage())
+}
+
+/// Get the number following a flag.
+fn number<T: std::str::FromStr>(args: &mut impl Iterator<Item = String>) -> T {
+    value(args).parse().unwrap_or_else(|_| usage())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(1);
+}
+
+fn main() {
+    let options = Options::parse();
+    let mut diverged = false;
+
+    for path in &options.programs {
+        let image = rumload::load_path(path).unwrap_or_else(|e| {
+            eprintln!("could not load {}: {}", path, e);
+            exit(1);
+        });
+        if image.entry_point != 0 {
+            eprintln!("{}: skipped, programs must start at offset 0", path);
+            continue;
+        }
+        diverged |= report(path, compare(&options, &image.words, options.max_steps));
+    }
+
+    if options.random > 0 {
+        let seed = options.seed.unwrap_or_else(rand::random);
+        for i in 0..options.random {
+            // Seed each program separately so any one can be reproduced
+            let mut rng = StdRng::seed_from_u64(seed.wrapping_add(i));
+            let program = rumgen::program(&mut rng, RANDOM_BLOCKS);
+            let result = compare(&options, &program, None);
+            if result.is_err() {
+                let name = format!("random program (seed {})", seed.wrapping_add(i));
+                diverged |= report(&name, result);
+            }
+        }
+        if !diverged {
+            println!(
+                "{} random programs (seeds {}..) agreed",
+                options.random, seed
+            );
+        }
+    }
+
+    if diverged {
+        exit(1);
+    }
+}
+
+/// Run `program` on the two backends or engines chosen by `options`, comparing them.
+fn compare(
+    options: &Options,
+    program: &[u32],
+    budget: Option<u64>,
+) -> Result<Agreement, Box<Divergence>> {
+    if options.engines {
+        return rumdiff::compare_engines(
+            FreeListMemory::checked(),
+            FreeListMemory::checked(),
+            program,
+            &options.input,
+            budget,
+            ENGINE_INTERVAL,
+        );
+    }
+    let mut lockstep = Lockstep::new(
+        BTreeMemory::new(),
+        FreeListMemory::checked(),
+        program,
+        &options.input,
+    );
+    lockstep.run(budget)
+}
+
+/// Print how a lockstep run of `name` ended.
+///
+/// # Returns
+/// Whether the backends diverged
+fn report(name: &str, result: Result<Agreement, Box<Divergence>>) -> bool {
+    match result {
+        Ok(Agreement { steps, outcome }) => {
+            let ending = match outcome {
+                Ok(Outcome::Halted) => "halted".to_string(),
+                Ok(Outcome::Yielded) => "step limit reached".to_string(),
+                Ok(Outcome::WaitingForInput) => "waiting for input".to_string(),
+                Err(fault) => fault.to_string(),
+            };
+            println!("{}: agreed for {} steps ({})", name, steps, ending);
+            false
+        }
+        Err(divergence) => {
+            println!("{}: {}", name, divergence);
+            true
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::{env, fs, process::exit};
+
+use rum::{rumaot, rumload};
+
+const USAGE: &str = "\
+Usage: um2rs <program> [output.rs]
+Translate a UM program into a Rust program that runs it with the rum crate,
+falling back to the interpreter for self-modifying code and LoadProgram from
+other segments. The source is written to stdout if no output file is given.
+To build it, e.g.: um2rs midmark.um examples/midmark.rs &&
+                   cargo run --release --example midmark";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, output) = match args.as_slice() {
+        [path] => (path, None),
+        [path, output] => (path, Some(output)),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }
+    };
+
+    let image = rumload::load_path(path).unwrap_or_else(|e| {
+        eprintln!("could not load {}: {}", path, e);
+        exit(1);
+    });
+    if image.entry_point != 0 {
+        eprintln!("{}: programs must start at offset 0", path);
+        exit(1);
+    }
+    let source = rumaot::translate(&image.words, path);
+    match output {
+        Some(output) => fs::write(output, source).unwrap_or_else(|e| {
+            eprintln!("could not write {}: {}", output, e);
+            exit(1);
+        }),
+        None => print!("{}", source),
+    }
+}
@@ -1,5 +1,15 @@
+pub mod rumaot;
+pub mod rumcov;
+pub mod rumdbg;
+pub mod rumdiff;
 pub mod rumdis;
+pub mod rumerr;
+pub mod rumgen;
 pub mod rumio;
 pub mod rumload;
 pub mod rummem;
-pub mod rumrun;
\ No newline at end of file
+pub mod rumprof;
+pub mod rumreplay;
+pub mod rumrun;
+pub mod rumsnap;
+pub mod rumtrace;
@@ -1,16 +1,491 @@
-use std::{env, fs::File};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use rum::*;
+use rum::{
+    rumio::IoDevice,
+    rummem::SegmentStore,
+    rumrun::{Machine, Observer, Outcome},
+    *,
+};
+
+const USAGE: &str = "\
+Usage: rum [options] <file.um/file.umz/file.um.gz/file.umx>
+       rum [options] -                      (read the program from stdin)
+       rum [options] --restore <state>
+       rum --pack <file.umx[.gz]> <program> (write a checksummed container)
+Options:
+  --debug                 start the interactive debugger (not with tracing,
+                          profiling, coverage, --save-state or --max-steps)
+  --memory <backend>      keep segments in `freelist` or `btree` memory
+  --checked-memory        fault on any use of an unmapped segment (freelist)
+  --engine <engine>       execute `plain` instructions or `fused`
+                          superinstructions (ignored when tracing, profiling,
+                          measuring coverage or saving state)
+  --max-steps <n>         stop after n instructions (exit status 2)
+  --max-words <n>         fault if the program maps more than n words in all
+  --max-segment-words <n> fault if the program maps a segment over n words
+  --max-segments <n>      fault if the program has more than n segments mapped
+  --strict-eof            fault when Input finds no more input, instead of
+                          loading 0xFFFFFFFF
+  --fault-report <fmt>    describe a fault on stderr as `text` (default) or
+                          as a line of `json`
+  --unbuffered            flush output after every byte rather than at each
+                          newline, Input, Halt or fault
+  --trace <file>          log each executed instruction to <file>
+  --trace-format <fmt>    trace as `text` (default) or `binary`
+  --trace-pc <lo>-<hi>    only trace instructions at these program counters
+  --trace-op <op,...>     only trace these operations (e.g. `loadp,map`)
+  --profile               print an execution profile to stderr after the run
+  --profile-json <file>   write the execution profile to <file> as JSON
+  --coverage <file>       write the number of times each word of each program
+                          loaded into segment 0 was executed to <file> as JSON
+  --save-state <file>     save the machine state to <file> when the run stops,
+                          and whenever rum receives SIGUSR1
+  --restore <file>        resume from a state saved by --save-state
+  --record <file>         log every input and output byte to <file>
+  --replay <file>         take input from a log written by --record, and exit
+                          with status 3 if the output differs from the log";
+
+/// Number of hot spots included in a profile.
+const HOT_SPOTS: usize = 20;
+
+/// Exit status used when `--max-steps` stops a program that has not halted.
+const EXIT_STEP_LIMIT: i32 = 2;
+
+/// Exit status used when `--replay` finds the program behaving differently from the recording.
+const EXIT_DIVERGED: i32 = 3;
+
+/// Instructions executed between checks for a SIGUSR1 save request.
+const SAVE_CHECK_INTERVAL: u64 = 1 << 20;
+
+/// Command-line options.
+#[derive(Default)]
+struct Options {
+    path: Option<String>,
+    debug: bool,
+    memory: Option<rummem::Backend>,
+    checked_memory: bool,
+    engine: rumrun::Engine,
+    strict_eof: bool,
+    unbuffered: bool,
+    fault_json: bool,
+    max_steps: Option<u64>,
+    limits: rummem::Limits,
+    trace: Option<String>,
+    trace_format: Option<rumtrace::Format>,
+    trace_filter: rumtrace::Filter,
+    profile: bool,
+    profile_json: Option<String>,
+    coverage: Option<String>,
+    save_state: Option<String>,
+    restore: Option<String>,
+    record: Option<String>,
+    pack: Option<String>,
+    replay: Option<String>,
+}
+
+/// What a run starts from.
+enum Start {
+    Program(rumload::Image),
+    Snapshot(rumsnap::Snapshot),
+}
+
+impl Start {
+    /// Build the machine to run, using `memory` and `io`, and configure it as directed by `options`.
+    fn machine<D: IoDevice, M: SegmentStore>(
+        self,
+        options: &Options,
+        mut memory: M,
+        io: D,
+    ) -> Machine<D, M> {
+        let mut machine = match self {
+            Start::Program(image) => {
+                memory.load_program(image.words);
+                let registers = [0; 8];
+                Machine::from_state(memory, io, registers, image.entry_point as usize, 0, false)
+            }
+            Start::Snapshot(snapshot) => snapshot.restore(memory, io),
+        };
+        machine.set_strict_eof(options.strict_eof);
+        machine.set_engine(options.engine);
+        machine
+    }
+}
+
+impl Options {
+    /// Parse the command line, printing usage and exiting if it is malformed.
+    fn parse() -> Options {
+        let mut options = Options::default();
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--debug" => options.debug = true,
+                "--checked-memory" => options.checked_memory = true,
+                "--strict-eof" => options.strict_eof = true,
+                "--unbuffered" => options.unbuffered = true,
+                "--memory" => {
+                    options.memory = Some(
+                        rummem::Backend::from_name(&value(&mut args)).unwrap_or_else(|| usage()),
+                    )
+                }
+                "--fault-report" => {
+                    options.fault_json = match value(&mut args).as_str() {
+                        "text" => false,
+                        "json" => true,
+                        _ => usage(),
+                    }
+                }
+                "--engine" => {
+                    options.engine =
+                        rumrun::Engine::from_name(&value(&mut args)).unwrap_or_else(|| usage())
+                }
+                "--max-steps" => options.max_steps = Some(number(&mut args)),
+                "--max-words" => options.limits.total_words = Some(number(&mut args)),
+                "--max-segment-words" => options.limits.segment_words = Some(number(&mut args)),
+                "--max-segments" => options.limits.live_segments = Some(number(&mut args)),
+                "--trace" => options.trace = Some(value(&mut args)),
+                "--trace-format" => {
+                    options.trace_format = match value(&mut args).as_str() {
+                        "text" => Some(rumtrace::Format::Text),
+                        "binary" => Some(rumtrace::Format::Binary),
+                        _ => usage(),
+                    }
+                }
+                "--trace-pc" => {
+                    options.trace_filter.pcs =
+                        Some(rumtrace::parse_pc_range(&value(&mut args)).unwrap_or_else(|| usage()))
+                }
+                "--trace-op" => {
+                    options.trace_filter.operations = Some(
+                        rumtrace::parse_operations(&value(&mut args)).unwrap_or_else(|| usage()),
+                    )
+                }
+                "--profile" => options.profile = true,
+                "--profile-json" => options.profile_json = Some(value(&mut args)),
+                "--coverage" => options.coverage = Some(value(&mut args)),
+                "--save-state" => options.save_state = Some(value(&mut args)),
+                "--restore" => options.restore = Some(value(&mut args)),
+                "--record" => options.record = Some(value(&mut args)),
+                "--pack" => options.pack = Some(value(&mut args)),
+                "--replay" => options.replay = Some(value(&mut args)),
+                _ if options.path.is_none() && !arg.starts_with("--") => options.path = Some(arg),
+                _ => usage(),
+            }
+        }
+        // Run either a program or a saved state, and only pack a program
+        if options.path.is_some() == options.restore.is_some()
+            || (options.pack.is_some() && options.restore.is_some())
+        {
+            usage();
+        }
+        // Only free-list memory can be checked. A saved state's backend is
+        // checked once it has been read.
+        let backend = match options.memory {
+            None if options.restore.is_none() => Some(rummem::Backend::default()),
+            memory => memory,
+        };
+        if options.checked_memory && backend == Some(rummem::Backend::BTree) {
+            usage();
+        }
+        // The debugger does its own I/O, and a session is either recorded or replayed
+        let sessions = options.record.is_some() as u8 + options.replay.is_some() as u8;
+        if sessions > 1 || (sessions > 0 && options.debug) {
+            usage();
+        }
+        // The debugger runs the machine itself, without observers, saving or a step limit
+        let observed = options.trace.is_some()
+            || options.profile
+            || options.profile_json.is_some()
+            || options.coverage.is_some();
+        if options.debug
+            && (observed || options.save_state.is_some() || options.max_steps.is_some())
+        {
+            usage();
+        }
+        options
+    }
+}
+
+/// Get the value following a flag.
+fn value(args: &mut impl Iterator<Item = String>) -> String {
+    args.next().unwrap_or_else(|| usage())
+}
+
+/// Get the number following a flag.
+fn number<T: std::str::FromStr>(args: &mut impl Iterator<Item = String>) -> T {
+    value(args).parse().unwrap_or_else(|_| usage())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(1);
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let argnum = args.len();
-    assert_eq!(argnum, 2, "Usage: rum <file.um/file.umz>");
-    let file = File::open(args.iter().nth(1).unwrap()).unwrap();
+    let options = Options::parse();
+
+    let (backend, start) = match (&options.path, &options.restore) {
+        (Some(path), _) => {
+            let program = rumload::load_path(path).unwrap_or_else(|e| {
+                eprintln!("could not load {}: {}", path, e);
+                exit(1);
+            });
+            if let Some(pack) = &options.pack {
+                write_pack(&program, pack);
+                return;
+            }
+            (options.memory.unwrap_or_default(), Start::Program(program))
+        }
+        (None, Some(path)) => {
+            let snapshot = File::open(path)
+                .and_then(|file| rumsnap::read(BufReader::new(file)))
+                .unwrap_or_else(|e| {
+                    eprintln!("could not restore {}: {}", path, e);
+                    exit(1);
+                });
+            if options
+                .memory
+                .is_some_and(|memory| memory != snapshot.backend)
+            {
+                eprintln!("{} was saved with {} memory", path, snapshot.backend.name());
+                exit(1);
+            }
+            if options.checked_memory && snapshot.backend != rummem::Backend::FreeList {
+                eprintln!(
+                    "{} was saved with {} memory, which cannot be checked",
+                    path,
+                    snapshot.backend.name()
+                );
+                exit(1);
+            }
+            (snapshot.backend, Start::Snapshot(snapshot))
+        }
+        (None, None) => usage(),
+    };
+
+    match backend {
+        rummem::Backend::BTree => run(&options, rummem::BTreeMemory::new(), start),
+        rummem::Backend::FreeList if options.checked_memory => {
+            run(&options, rummem::FreeListMemory::checked(), start)
+        }
+        rummem::Backend::FreeList => run(&options, rummem::FreeListMemory::new(), start),
+    }
+}
+
+/// Run from `start` with segments kept in `memory`, as directed by `options`.
+fn run<M: SegmentStore>(options: &Options, memory: M, start: Start) {
+    let memory = rummem::Limited::new(memory, options.limits);
+    if options.debug {
+        // Commands and program input both come from stdin. Stdin's own
+        // buffer is shared, so commands are read from it a byte at a time to
+        // leave the rest of the input for the program.
+        let mut io = rumio::stdio();
+        io.set_buffered(!options.unbuffered);
+        let machine = start.machine(options, memory, io);
+        let commands = rumdbg::commands(io::stdin());
+        if let Err(e) = rumdbg::Debugger::new(machine, commands, io::stderr()).run() {
+            eprintln!("debugger stopped: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    let mut io = rumio::Streams::new(io::stdin().lock(), BufWriter::new(io::stdout().lock()));
+    io.set_buffered(!options.unbuffered);
+    if let Some(path) = &options.record {
+        let log = create(path);
+        let mut machine = start.machine(options, memory, rumreplay::Recorder::new(io, log));
+        let result = drive(options, &mut machine);
+        if let Err(e) = machine.io_mut().finish() {
+            eprintln!("could not write session log: {}", e);
+        }
+        exit_for(options, &machine, result);
+    } else if let Some(path) = &options.replay {
+        let entries = File::open(path)
+            .and_then(|file| rumreplay::read(BufReader::new(file)))
+            .unwrap_or_else(|e| {
+                eprintln!("could not replay {}: {}", path, e);
+                exit(1);
+            });
+        let mut machine = start.machine(options, memory, rumreplay::Replay::new(entries, io));
+        let result = drive(options, &mut machine);
+        if let Some(divergence) = machine.io_mut().finish() {
+            eprintln!("replay diverged: {}", divergence);
+            exit(EXIT_DIVERGED);
+        }
+        exit_for(options, &machine, result);
+    } else {
+        let mut machine = start.machine(options, memory, io);
+        let result = drive(options, &mut machine);
+        exit_for(options, &machine, result);
+    }
+}
+
+/// Run `machine` as directed by `options`, then write out any trace, profile or saved state.
+fn drive<D: IoDevice, M: SegmentStore>(
+    options: &Options,
+    machine: &mut Machine<D, M>,
+) -> Result<Outcome, rumerr::Fault> {
+    let tracer = options.trace.as_ref().map(|path| {
+        rumtrace::Tracer::new(
+            create(path),
+            options.trace_format.unwrap_or(rumtrace::Format::Text),
+            options.trace_filter.clone(),
+        )
+    });
+
+    let profiling = options.profile || options.profile_json.is_some();
+    // Create the profile and coverage files before running, so a bad path
+    // does not waste the run
+    let profile_json = options.profile_json.as_deref().map(create);
+    let coverage_json = options.coverage.as_deref().map(create);
+    let coverage = options.coverage.as_ref().map(|_| rumcov::Coverage::new());
+    let mut observers = (tracer, (profiling.then(rumprof::Profiler::new), coverage));
+
+    let result = match (&observers, options.max_steps, &options.save_state) {
+        (_, budget, Some(path)) => {
+            let requested = Arc::new(AtomicBool::new(false));
+            if let Err(e) =
+                signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&requested))
+            {
+                eprintln!("could not listen for SIGUSR1: {}", e);
+            }
+            run_saving(machine, budget, &mut observers, &requested, path)
+        }
+        ((None, (None, None)), Some(budget), None) => machine.run_for(budget),
+        ((None, (None, None)), None, None) => machine.run().map(|()| Outcome::Halted),
+        (_, budget, None) => machine.run_observed(budget, &mut observers),
+    };
+    // The machine only flushes output itself when it halts or faults
+    if let Err(e) = machine.io_mut().flush() {
+        eprintln!("could not write output: {}", e);
+    }
+    if let Some(path) = &options.save_state {
+        save_state(machine, path);
+    }
 
-    let program = rumload::load(file);
-    let mut memory = rummem::Memory::new();
-    rummem::load_program(&mut memory, program);
+    let (tracer, (profiler, coverage)) = observers;
+    if let Some(tracer) = tracer {
+        if let Err(e) = tracer.finish() {
+            eprintln!("could not write trace: {}", e);
+        }
+    }
+    if let Some(profiler) = profiler {
+        let report = profiler.report(machine, HOT_SPOTS);
+        if options.profile {
+            eprintln!("{}", report);
+        }
+        if let Some(file) = profile_json {
+            if let Err(e) = report.write_json(file) {
+                eprintln!("could not write profile: {}", e);
+            }
+        }
+    }
+    if let (Some(coverage), Some(file)) = (coverage, coverage_json) {
+        if let Err(e) = coverage.report().write_json(file) {
+            eprintln!("could not write coverage: {}", e);
+        }
+    }
+    result
+}
+
+/// Exit, reporting how the run ended if the program did not halt.
+fn exit_for<D: IoDevice, M: SegmentStore>(
+    options: &Options,
+    machine: &Machine<D, M>,
+    result: Result<Outcome, rumerr::Fault>,
+) {
+    match result {
+        Ok(Outcome::Halted) => {}
+        Ok(_) => {
+            eprintln!(
+                "step limit reached: {} instructions executed without halting",
+                machine.steps()
+            );
+            exit(EXIT_STEP_LIMIT);
+        }
+        Err(fault) if options.fault_json => {
+            let report = rumerr::FaultReport::new(
+                &fault,
+                *machine.registers(),
+                machine.memory().live_segments(),
+                machine.steps(),
+            );
+            if let Err(e) = report.write_json(io::stderr().lock()) {
+                eprintln!("could not write fault report: {}", e);
+            }
+            exit(1);
+        }
+        Err(fault) => {
+            eprintln!("{}", fault);
+            eprintln!("registers: {:08x?}", machine.registers());
+            exit(1);
+        }
+    }
+}
+
+/// Run `machine` like `Machine::run_observed`, saving its state to `path`
+/// whenever `requested` is set (by SIGUSR1).
+fn run_saving<D: IoDevice, M: SegmentStore, O: Observer>(
+    machine: &mut Machine<D, M>,
+    budget: Option<u64>,
+    observer: &mut O,
+    requested: &AtomicBool,
+    path: &str,
+) -> Result<Outcome, rumerr::Fault> {
+    let limit = budget.map(|budget| machine.steps() + budget);
+    loop {
+        let slice = limit.map_or(SAVE_CHECK_INTERVAL, |limit| {
+            (limit - machine.steps()).min(SAVE_CHECK_INTERVAL)
+        });
+        let outcome = machine.run_observed(Some(slice), observer);
+        if requested.swap(false, Ordering::Relaxed) {
+            save_state(machine, path);
+        }
+        match outcome? {
+            Outcome::Halted => return Ok(Outcome::Halted),
+            Outcome::Yielded if limit == Some(machine.steps()) => return Ok(Outcome::Yielded),
+            _ => {}
+        }
+    }
+}
+
+/// Create the file at `path` for writing, exiting if it cannot be created.
+fn create(path: &str) -> BufWriter<File> {
+    let file = File::create(path).unwrap_or_else(|e| {
+        eprintln!("could not create {}: {}", path, e);
+        exit(1);
+    });
+    BufWriter::new(file)
+}
+
+/// Save the state of `machine` to `path`, replacing the previous save only
+/// once the new one is complete.
+fn save_state<D: IoDevice, M: SegmentStore>(machine: &Machine<D, M>, path: &str) {
+    let partial = format!("{}.partial", path);
+    let result = File::create(&partial)
+        .and_then(|file| rumsnap::write(machine, BufWriter::new(file)))
+        .and_then(|()| fs::rename(&partial, path));
+    if let Err(e) = result {
+        eprintln!("could not save state to {}: {}", path, e);
+    }
+}
 
-    rumrun::execute(&mut memory);
+/// Write `image` to `path` as a container, compressed if `path` ends in `.gz`.
+fn write_pack(image: &rumload::Image, path: &str) {
+    let result = File::create(path)
+        .and_then(|file| rumload::write_container(image, path.ends_with(".gz"), file));
+    if let Err(e) = result {
+        eprintln!("could not write {}: {}", path, e);
+        exit(1);
+    }
 }
@@ -0,0 +1,543 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    io::{self, BufWriter, ErrorKind, StdinLock, StdoutLock},
+    process::exit,
+};
+
+use crate::{
+    rumdis::{self, Decoded, Operation},
+    rumerr::{Fault, UmError},
+    rumio::{IoDevice, Streams},
+    rummem::{self, SegmentStore},
+    rumrun::{Machine, Registers},
+};
+
+/// The I/O device of a translated program: stdin, and buffered stdout as `rum` uses.
+pub type Stdio = Streams<StdinLock<'static>, BufWriter<StdoutLock<'static>>>;
+
+/// The start of a translated program, following its header comment.
+const IMPORTS: &str = "
+#![allow(clippy::all)]
+
+use rum::rumaot::{Runtime, Stop};
+use rum::rumerr::UmError;
+
+";
+
+/// The end of the program's words, followed by the compiled code.
+const RUN: &str = "];
+
+/// Stop with a fault if `$result` is an error.
+#[allow(unused_macros)]
+macro_rules! check {
+    ($result:expr, $pc:expr, $r:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(error) => return Some(Stop::Fault(error, $pc, $r)),
+        }
+    };
+}
+
+fn main() {
+    rum::rumaot::main(&PROGRAM, run);
+}
+
+fn run(rt: &mut Runtime) -> Stop {
+    let mut r = [0u32; 8];
+    let mut pc = 0;
+    loop {
+        let stop = match pc / CHUNK {
+";
+
+/// Program counters compiled into each function, so that no function is
+/// too large for the compiler.
+const CHUNK: usize = 1024;
+
+/// Why a translated program stopped running compiled code.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Stop {
+    Halt,
+    /// An instruction failed.
+    Fault(UmError, usize, Registers),
+    /// The program reached code that has been modified since it was
+    /// compiled, or a LoadProgram from another segment, which the compiled
+    /// code cannot follow, so the interpreter takes over from that instruction.
+    Interpret(usize, Registers),
+}
+
+/// The memory and I/O of a program translated by `translate`.
+///
+/// Compiled code keeps the registers and program counter itself, and only
+/// calls into the runtime for memory and I/O, and to ask whether the code
+/// compiled for a match arm is still what segment 0 holds.
+pub struct Runtime<D: IoDevice = Stdio, M: SegmentStore = rummem::Memory> {
+    memory: M,
+    io: D,
+    /// For each word of segment 0, the arm other than its own whose
+    /// instructions include it, if any. Only jump targets have arms of more
+    /// than one instruction, and these never overlap.
+    covering: Vec<usize>,
+    /// Whether any of the instructions in the arm for each program counter
+    /// have since been overwritten.
+    stale: Vec<bool>,
+}
+
+impl<D: IoDevice, M: SegmentStore> Runtime<D, M> {
+    /// Create a runtime whose segment 0 has already been loaded into `memory`,
+    /// for the code that `translate` compiles from that segment.
+    pub fn new(memory: M, io: D) -> Runtime<D, M> {
+        let program: Vec<Decoded> = memory
+            .program()
+            .iter()
+            .map(|&word| rumdis::decode(word))
+            .collect();
+        let targets = jump_targets(&program);
+        let mut covering: Vec<usize> = (0..program.len()).collect();
+        for &target in targets.range(..program.len()) {
+            let end = block_end(&program, &targets, target);
+            covering[target..end].fill(target);
+        }
+        Runtime {
+            memory,
+            io,
+            stale: vec![false; covering.len()],
+            covering,
+        }
+    }
+
+    /// Check whether the arm for `pc` must leave its instructions to the interpreter.
+    pub fn stale(&self, pc: usize) -> bool {
+        self.stale[pc]
+    }
+
+    pub fn io(&self) -> &D {
+        &self.io
+    }
+
+    pub fn load(&mut self, segment: u32, offset: u32) -> Result<u32, UmError> {
+        self.memory.load(segment, offset)
+    }
+
+    /// Store a word, marking the arms that include it as stale if it is in
+    /// segment 0 and differs from what was there.
+    pub fn store(&mut self, segment: u32, offset: u32, value: u32) -> Result<(), UmError> {
+        let old = match segment {
+            0 => self.memory.program().get(offset as usize).copied(),
+            _ => None,
+        };
+        self.memory.store(value, segment, offset)?;
+        if old.is_some_and(|old| old != value) {
+            let index = offset as usize;
+            self.stale[index] = true;
+            self.stale[self.covering[index]] = true;
+        }
+        Ok(())
+    }
+
+    pub fn map(&mut self, length: u32) -> Result<u32, UmError> {
+        self.memory.map(length)
+    }
+
+    pub fn unmap(&mut self, segment: u32) -> Result<(), UmError> {
+        self.memory.unmap(segment)
+    }
+
+    pub fn output(&mut self, value: u32) {
+        // A closed output device does not stop the machine
+        let _ = self.io.output(value as u8);
+    }
+
+    /// Get a byte of input, or all ones at the end of input.
+    pub fn input(&mut self) -> u32 {
+        loop {
+            match self.io.input() {
+                Ok(Some(value)) => return value as u32,
+                // Only an interpreter can wait for input, so keep asking
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                _ => return u32::MAX,
+            }
+        }
+    }
+
+    /// Finish a run of compiled code, handing over to the interpreter if it needs to.
+    ///
+    /// # Errors
+    /// The `Fault` that stopped the compiled code or the interpreter
+    pub fn finish(mut self, stop: Stop) -> Result<(), Fault> {
+        match stop {
+            Stop::Halt => {
+                let _ = self.io.flush();
+                Ok(())
+            }
+            Stop::Fault(error, program_counter, _) => {
+                let _ = self.io.flush();
+                Err(Fault {
+                    error,
+                    program_counter,
+                    instruction: self.memory.program().get(program_counter).copied(),
+                })
+            }
+            Stop::Interpret(program_counter, registers) => {
+                Machine::from_state(self.memory, self.io, registers, program_counter, 0, false)
+                    .run()
+            }
+        }
+    }
+}
+
+/// Run a translated program against stdin and stdout, as its `main` function.
+///
+/// # Arguments
+/// - `program`: the words of segment 0
+/// - `run`: the compiled code
+pub fn main(program: &[u32], run: fn(&mut Runtime) -> Stop) {
+    let mut memory = rummem::Memory::new();
+    memory.load_program(program.to_vec());
+    let io = Streams::new(io::stdin().lock(), BufWriter::new(io::stdout().lock()));
+    let mut runtime = Runtime::new(memory, io);
+    let stop = run(&mut runtime);
+    if let Err(fault) = runtime.finish(stop) {
+        eprintln!("{}", fault);
+        exit(1);
+    }
+}
+
+/// Translate a program into Rust source for a binary that runs it with `main`.
+///
+/// The program counter selects a match arm, in a function for each
+/// `CHUNK` of the program. An arm runs the instruction there, and if the
+/// program counter may be the target of a jump (a value loaded by
+/// LoadValue, or the instruction after a LoadProgram), the instructions
+/// following it up to the next such target or jump.
+///
+/// # Arguments
+/// - `program`: the words of segment 0
+/// - `name`: where the program came from, for the header comment
+pub fn translate(program: &[u32], name: &str) -> String {
+    let decoded: Vec<Decoded> = program.iter().map(|&word| rumdis::decode(word)).collect();
+    let targets = jump_targets(&decoded);
+
+    let mut source = String::new();
+    let _ = writeln!(source, "//! {} translated to Rust by um2rs.", name);
+    source.push_str(IMPORTS);
+    let _ = writeln!(source, "static PROGRAM: [u32; {}] = [", program.len());
+    for words in program.chunks(8) {
+        let words: Vec<String> = words.iter().map(|word| format!("0x{:08x}", word)).collect();
+        let _ = writeln!(source, "    {},", words.join(", "));
+    }
+    source.push_str(RUN);
+    let chunks = program.len().div_ceil(CHUNK);
+    for chunk in 0..chunks {
+        let _ = writeln!(
+            source,
+            "            {} => run_{}(rt, &mut r, &mut pc),",
+            chunk, chunk
+        );
+    }
+    let _ = writeln!(
+        source,
+        "            _ => Some(Stop::Fault(UmError::PcOutOfRange {{ length: {} }}, pc, r)),",
+        program.len()
+    );
+    source.push_str("        };\n");
+    source.push_str("        if let Some(stop) = stop {\n            return stop;\n        }\n");
+    source.push_str("    }\n}\n");
+    let _ = writeln!(source, "\nconst CHUNK: usize = {};", CHUNK);
+
+    for chunk in 0..chunks {
+        let _ = writeln!(source);
+        let _ = writeln!(source, "#[allow(unused_mut, unreachable_code)]");
+        let _ = writeln!(
+            source,
+            "fn run_{}(rt: &mut Runtime, registers: &mut [u32; 8], counter: &mut usize) -> Option<Stop> {{",
+            chunk
+        );
+        source.push_str("    let mut r = *registers;\n    let mut pc = *counter;\n");
+        source.push_str("    loop {\n        match pc {\n");
+        let end = program.len().min((chunk + 1) * CHUNK);
+        for pc in chunk * CHUNK..end {
+            translate_arm(&mut source, program, &decoded, &targets, pc);
+        }
+        // Past the end of the program, e.g. by running off it, is a fault;
+        // anywhere else belongs to another chunk
+        let _ = writeln!(
+            source,
+            "            _ if pc >= {} => {{\n                return Some(Stop::Fault(UmError::PcOutOfRange {{ length: {} }}, pc, r));\n            }}",
+            program.len(),
+            program.len()
+        );
+        source.push_str("            _ => {\n");
+        source.push_str("                *registers = r;\n                *counter = pc;\n");
+        source.push_str("                return None;\n            }\n");
+        source.push_str("        }\n    }\n}\n");
+    }
+    source
+}
+
+/// Write the match arm for `pc`.
+fn translate_arm(
+    source: &mut String,
+    program: &[u32],
+    decoded: &[Decoded],
+    targets: &BTreeSet<usize>,
+    pc: usize,
+) {
+    let end = block_end(decoded, targets, pc);
+    let _ = writeln!(source, "            {} => {{", pc);
+    let _ = writeln!(
+        source,
+        "                if rt.stale({pc}) {{ return Some(Stop::Interpret({pc}, r)); }}"
+    );
+    for next in pc..end {
+        let _ = writeln!(
+            source,
+            "                // {}: {}",
+            next,
+            rumdis::disassemble(&program[next])
+        );
+        translate_instruction(source, decoded[next], program[next], next);
+        // The store may have overwritten one of the instructions still to come
+        if decoded[next].operation == Some(Operation::StoreSegment) && next + 1 < end {
+            let _ = writeln!(
+                source,
+                "                if r[{}] == 0 && rt.stale({}) {{ return Some(Stop::Interpret({}, r)); }}",
+                decoded[next].a,
+                pc,
+                next + 1
+            );
+        }
+    }
+    if falls_through(decoded[end - 1]) {
+        let _ = writeln!(source, "                pc = {};", end);
+    }
+    let _ = writeln!(source, "            }}");
+}
+
+/// Find the end of the instructions compiled into the match arm for `pc`.
+///
+/// If `pc` is a jump target, the arm runs on until the next target or
+/// jump, otherwise it runs a single instruction.
+fn block_end(program: &[Decoded], targets: &BTreeSet<usize>, pc: usize) -> usize {
+    let mut next = pc;
+    loop {
+        next += 1;
+        if !falls_through(program[next - 1])
+            || !targets.contains(&pc)
+            || targets.contains(&next)
+            || next == program.len()
+        {
+            return next;
+        }
+    }
+}
+
+/// Find the program counters that a LoadProgram may jump to: the values
+/// loaded by LoadValue that are inside the program, and the instruction
+/// after each LoadProgram (a likely return address), as well as 0.
+fn jump_targets(program: &[Decoded]) -> BTreeSet<usize> {
+    let mut targets = BTreeSet::from([0]);
+    for (pc, decoded) in program.iter().enumerate() {
+        match decoded.operation {
+            Some(Operation::LoadValue) if (decoded.value as usize) < program.len() => {
+                targets.insert(decoded.value as usize);
+            }
+            Some(Operation::LoadProgram) => {
+                targets.insert(pc + 1);
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+/// Check whether execution continues with the next instruction after `decoded`.
+fn falls_through(decoded: Decoded) -> bool {
+    !matches!(
+        decoded.operation,
+        Some(Operation::Halt | Operation::LoadProgram) | None
+    )
+}
+
+/// Write the Rust statements for one instruction at `pc`.
+fn translate_instruction(source: &mut String, decoded: Decoded, word: u32, pc: usize) {
+    let Decoded {
+        operation,
+        a,
+        b,
+        c,
+        value,
+    } = decoded;
+    let indent = "                ";
+    let statement = match operation {
+        Some(Operation::ConditionalMove) => {
+            format!("if r[{}] != 0 {{ r[{}] = r[{}]; }}", c, a, b)
+        }
+        Some(Operation::LoadSegment) => {
+            format!("r[{}] = check!(rt.load(r[{}], r[{}]), {}, r);", a, b, c, pc)
+        }
+        Some(Operation::StoreSegment) => {
+            format!("check!(rt.store(r[{}], r[{}], r[{}]), {}, r);", a, b, c, pc)
+        }
+        Some(Operation::Add) => format!("r[{}] = r[{}].wrapping_add(r[{}]);", a, b, c),
+        Some(Operation::Multiply) => format!("r[{}] = r[{}].wrapping_mul(r[{}]);", a, b, c),
+        Some(Operation::Divide) => format!(
+            "r[{}] = check!(r[{}].checked_div(r[{}]).ok_or(UmError::DivideByZero), {}, r);",
+            a, b, c, pc
+        ),
+        Some(Operation::Nand) => format!("r[{}] = !(r[{}] & r[{}]);", a, b, c),
+        Some(Operation::Halt) => "return Some(Stop::Halt);".to_string(),
+        Some(Operation::Map) => format!("r[{}] = check!(rt.map(r[{}]), {}, r);", b, c, pc),
+        Some(Operation::Unmap) => format!("check!(rt.unmap(r[{}]), {}, r);", c, pc),
+        Some(Operation::Output) => format!("rt.output(r[{}]);", c),
+        Some(Operation::Input) => format!("r[{}] = rt.input();", c),
+        Some(Operation::LoadProgram) => format!(
+            "if r[{b}] != 0 {{ return Some(Stop::Interpret({pc}, r)); }}\n\
+             {indent}pc = r[{c}] as usize;"
+        ),
+        Some(Operation::LoadValue) => format!("r[{}] = {};", a, value),
+        None => format!(
+            "return Some(Stop::Fault(UmError::InvalidOpcode({}), {}, r));",
+            word >> 28,
+            pc
+        ),
+    };
+    let _ = writeln!(source, "{}{}", indent, statement);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rumio::Slow;
+
+    // 0: loadv r1, 4
+    // 1: loadv r2, 'a'
+    // 2: output r2
+    // 3: loadp r0, r1
+    // 4: loadv r3, 3
+    // 5: loadv r4, 0x7000 (the high bits of halt)
+    // 6: loadv r5, 0x10000
+    // 7: mult r4, r4, r5
+    // 8: store r0, r3, r4 (overwrites 3 with halt)
+    // 9: loadv r6, 1
+    // 10: loadp r0, r6
+    const PROGRAM: [u32; 11] = [
+        0xD200_0004,
+        0xD400_0061,
+        0xA000_0002,
+        0xC000_0001,
+        0xD600_0003,
+        0xD800_7000,
+        0xDA01_0000,
+        0x4000_0125,
+        0x2000_001C,
+        0xDC00_0001,
+        0xC000_0006,
+    ];
+
+    fn runtime(output: &mut Vec<u8>) -> Runtime<Streams<&'static [u8], &mut Vec<u8>>> {
+        let mut memory = rummem::Memory::new();
+        memory.load_program(PROGRAM.to_vec());
+        Runtime::new(memory, Streams::new(&b""[..], output))
+    }
+
+    /// Get the source of the match arm for `pc`.
+    fn arm(source: &str, pc: usize) -> &str {
+        let start = source.find(&format!("            {} => {{", pc)).unwrap();
+        let end = source[start..].find("\n            }\n").unwrap();
+        &source[start..start + end]
+    }
+
+    #[test]
+    fn translates_blocks() {
+        let source = translate(&PROGRAM, "test.um");
+        assert!(source.contains("static PROGRAM: [u32; 11]"));
+        // 1 is a jump target, so its arm runs on until the next one
+        let one = arm(&source, 1);
+        assert!(one.contains("r[2] = 97;"));
+        assert!(one.contains("rt.output(r[2]);"));
+        assert!(one.contains("pc = 3;"));
+        // 2 is not, so its arm runs just that instruction
+        let two = arm(&source, 2);
+        assert!(two.contains("rt.output(r[2]);"));
+        assert!(two.contains("pc = 3;"));
+        assert!(!two.contains("r[2] = 97;"));
+        // A store may leave the rest of the block stale, and a block ends at
+        // a LoadProgram, which leaves other segments to the interpreter
+        let four = arm(&source, 4);
+        assert!(four.contains("if rt.stale(4) { return Some(Stop::Interpret(4, r)); }"));
+        assert!(four.contains("check!(rt.store(r[0], r[3], r[4]), 8, r);"));
+        assert!(
+            four.contains("if r[0] == 0 && rt.stale(4) { return Some(Stop::Interpret(9, r)); }")
+        );
+        assert!(four.contains("if r[0] != 0 { return Some(Stop::Interpret(10, r)); }"));
+        assert!(four.ends_with("pc = r[6] as usize;"));
+    }
+
+    #[test]
+    fn faults_past_the_end() {
+        // loadv r1, 'A'; output r1 (and off the end of the program)
+        let source = translate(&[0xD200_0041, 0xA000_0001], "test.um");
+        assert!(arm(&source, 0).ends_with("pc = 2;"));
+        assert!(source.contains(
+            "            _ if pc >= 2 => {\n                return Some(Stop::Fault(UmError::PcOutOfRange { length: 2 }, pc, r));"
+        ));
+    }
+
+    #[test]
+    fn finds_jump_targets() {
+        let program: Vec<Decoded> = PROGRAM.iter().map(|&word| rumdis::decode(word)).collect();
+        assert_eq!(jump_targets(&program), BTreeSet::from([0, 1, 3, 4, 11]));
+    }
+
+    #[test]
+    fn stores_mark_code_stale() {
+        let mut output = Vec::new();
+        let mut runtime = runtime(&mut output);
+        runtime.store(0, 5, PROGRAM[5]).unwrap();
+        assert!(!runtime.stale(4));
+        runtime.store(0, 5, 0).unwrap();
+        assert!((0..PROGRAM.len()).all(|pc| runtime.stale(pc) == (pc == 4 || pc == 5)));
+        assert_eq!(
+            runtime.store(0, 11, 0),
+            Err(UmError::OutOfBounds {
+                segment: 0,
+                offset: 11,
+                length: 11
+            })
+        );
+    }
+
+    #[test]
+    fn interpreter_takes_over() {
+        // As the compiled code would run up to 3, which the store at 8 made stale
+        let mut output = Vec::new();
+        let mut runtime = runtime(&mut output);
+        runtime.output(b'a' as u32);
+        runtime.store(0, 3, 0x7000_0000).unwrap();
+        runtime.output(b'a' as u32);
+        assert!(runtime.stale(3));
+        let registers = [0, 4, b'a' as u32, 3, 0x7000_0000, 0x10000, 1, 0];
+        assert_eq!(runtime.finish(Stop::Interpret(3, registers)), Ok(()));
+        assert_eq!(output, b"aa");
+    }
+
+    #[test]
+    fn polls_for_input() {
+        let mut memory = rummem::Memory::new();
+        memory.load_program(PROGRAM.to_vec());
+        // Far more polls than there would be room on the stack for
+        let mut runtime = Runtime::new(memory, Slow(1_000_000));
+        assert_eq!(runtime.input(), b'x' as u32);
+    }
+
+    #[test]
+    fn reports_faults() {
+        let mut output = Vec::new();
+        let fault = runtime(&mut output)
+            .finish(Stop::Fault(UmError::DivideByZero, 3, [0; 8]))
+            .unwrap_err();
+        assert_eq!(fault.program_counter, 3);
+        assert_eq!(fault.instruction, Some(PROGRAM[3]));
+    }
+}
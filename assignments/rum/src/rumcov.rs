@@ -0,0 +1,193 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    rumdis::{self, Operation},
+    rumio::IoDevice,
+    rumload,
+    rummem::SegmentStore,
+    rumrun::{Machine, Observer},
+};
+
+/// Counts how often each word of segment 0 is executed, separately for
+/// each program that LoadProgram puts there.
+#[derive(Default)]
+pub struct Coverage {
+    /// Hits for each program run before the current one, by checksum.
+    programs: HashMap<u32, Vec<u64>>,
+    /// The checksum and hits of the program being run, or `None` if segment
+    /// 0 may have been replaced since the last instruction.
+    current: Option<(u32, Vec<u64>)>,
+    /// The program counter of the instruction being executed, and whether it
+    /// replaces the program. It is only counted once it completes, as an
+    /// Input waiting for input is executed again.
+    pending: Option<(usize, bool)>,
+}
+
+/// The coverage of a run, as written by `rum --coverage`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+pub struct Report {
+    /// Executions of each word, by the `key` of the program as loaded into
+    /// segment 0. A program that was modified after loading is counted
+    /// under its original key.
+    pub programs: BTreeMap<String, Vec<u64>>,
+}
+
+/// Get the key a program's coverage is reported under: the CRC-32 of the
+/// program (see `rumload::checksum`) in hexadecimal.
+pub fn key(program: &[u32]) -> String {
+    format!("{:08x}", rumload::checksum(program))
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Summarize the run so far.
+    pub fn report(&self) -> Report {
+        let mut programs: BTreeMap<String, Vec<u64>> = self
+            .programs
+            .iter()
+            .map(|(checksum, hits)| (format!("{:08x}", checksum), hits.clone()))
+            .collect();
+        if let Some((checksum, hits)) = &self.current {
+            programs.insert(format!("{:08x}", checksum), hits.clone());
+        }
+        Report { programs }
+    }
+}
+
+impl Observer for Coverage {
+    fn before<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        let programs = &mut self.programs;
+        self.current.get_or_insert_with(|| {
+            let program = machine.memory().program();
+            let checksum = rumload::checksum(program);
+            let hits = programs
+                .remove(&checksum)
+                .unwrap_or_else(|| vec![0; program.len()]);
+            (checksum, hits)
+        });
+
+        // A LoadProgram from another segment replaces the program
+        let word = machine.current_word().unwrap_or(0);
+        let decoded = rumdis::decode(word);
+        let replaces = decoded.operation == Some(Operation::LoadProgram)
+            && machine.registers()[decoded.b as usize] != 0;
+        self.pending = Some((machine.program_counter(), replaces));
+    }
+
+    fn after<D: IoDevice, M: SegmentStore>(&mut self, _machine: &Machine<D, M>) {
+        let (pc, replaces) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        if let Some((_, hits)) = &mut self.current {
+            if let Some(count) = hits.get_mut(pc) {
+                *count += 1;
+            }
+        }
+        if replaces {
+            if let Some((checksum, hits)) = self.current.take() {
+                self.programs.insert(checksum, hits);
+            }
+        }
+    }
+}
+
+impl Report {
+    /// Get the hits for each word of `program`, if it was run.
+    pub fn hits(&self, program: &[u32]) -> Option<&[u64]> {
+        self.programs.get(&key(program)).map(Vec::as_slice)
+    }
+
+    /// Write the report as JSON.
+    pub fn write_json(&self, out: impl Write) -> io::Result<()> {
+        serde_json::to_writer(out, self)?;
+        Ok(())
+    }
+
+    /// Read a report written by `write_json`.
+    ///
+    /// # Errors
+    /// - `InvalidData` if `input` is not a coverage report
+    pub fn read_json(input: impl Read) -> io::Result<Report> {
+        Ok(serde_json::from_reader(input)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumasm::rumasm::{halt, input, load, loadp, loadv, map, store};
+
+    use super::*;
+    use crate::rumio::Slow;
+
+    /// A program that copies its last word (halt) into a new segment after
+    /// a zero word (cmov r0, r0, r0), and loads that segment as the program.
+    fn program() -> Vec<u32> {
+        vec![
+            loadv(1, 2),
+            map(2, 1),
+            loadv(5, 8),
+            load(4, 0, 5),
+            loadv(6, 1),
+            store(2, 6, 4),
+            loadv(7, 0),
+            loadp(2, 7),
+            halt(),
+        ]
+    }
+
+    #[test]
+    fn covers_each_program() {
+        let program = program();
+        let mut machine = Machine::from_program(program.clone(), &b""[..], Vec::new());
+        let mut coverage = Coverage::new();
+        machine.run_observed(None, &mut coverage).unwrap();
+        let report = coverage.report();
+
+        assert_eq!(report.programs.len(), 2);
+        assert_eq!(
+            report.hits(&program),
+            Some(&[1, 1, 1, 1, 1, 1, 1, 1, 0][..])
+        );
+        assert_eq!(report.hits(&[0, halt()]), Some(&[1, 1][..]));
+        assert_eq!(report.hits(&[halt()]), None);
+    }
+
+    #[test]
+    fn counts_waiting_input_once() {
+        let program = vec![input(1), halt()];
+        let mut memory = crate::rummem::Memory::new();
+        memory.load_program(program.clone());
+        let mut machine = Machine::with_io(memory, Slow(3));
+        let mut coverage = Coverage::new();
+        machine.run_observed(None, &mut coverage).unwrap();
+        assert_eq!(coverage.report().hits(&program), Some(&[1, 1][..]));
+    }
+
+    #[test]
+    fn accumulates_reloads() {
+        // Jumps within segment 0 do not change the program
+        let program = vec![loadv(1, 2), loadp(0, 1), halt()];
+        let mut coverage = Coverage::new();
+        for _ in 0..2 {
+            let mut machine = Machine::from_program(program.clone(), &b""[..], Vec::new());
+            machine.run_observed(None, &mut coverage).unwrap();
+        }
+        let report = coverage.report();
+        assert_eq!(report.programs.len(), 1);
+        assert_eq!(report.hits(&program), Some(&[2, 2, 2][..]));
+
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        assert_eq!(Report::read_json(&json[..]).unwrap(), report);
+        assert!(Report::read_json(&b"[]"[..]).is_err());
+    }
+}
@@ -0,0 +1,762 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use crate::{
+    rumerr::Fault,
+    rumio::IoDevice,
+    rummem::{self, Backend, SegmentStore},
+    rumrun::{Machine, Status},
+    rumsnap::Snapshot,
+};
+
+mod history;
+
+pub use history::History;
+
+/// Instructions between checkpoints, until there are `MAX_CHECKPOINTS`.
+const CHECKPOINT_INTERVAL: u64 = 10_000;
+
+/// The most checkpoints kept. Beyond this, every other one is dropped and
+/// they are taken half as often, so memory stays bounded however long the
+/// program runs, at the cost of replaying further to reverse.
+const MAX_CHECKPOINTS: usize = 64;
+
+/// Read debugger commands from `input` without reading ahead, so that when
+/// `input` is shared with the program (e.g. both use stdin) the bytes after
+/// each command line are left for the program's Input.
+pub fn commands<R: Read>(input: R) -> BufReader<R> {
+    BufReader::with_capacity(1, input)
+}
+
+/// A debugger command, as typed at the `(rum)` prompt.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Command {
+    Break(usize),
+    Delete(usize),
+    Step(u64),
+    Continue,
+    ReverseStep(u64),
+    ReverseContinue,
+    Registers,
+    Examine {
+        segment: u32,
+        offset: usize,
+        count: usize,
+    },
+    Watch {
+        segment: u32,
+        offset: usize,
+    },
+    Unwatch {
+        segment: u32,
+        offset: usize,
+    },
+    Info,
+    Help,
+    Quit,
+}
+
+const HELP: &str = "\
+break <pc>             stop before executing the instruction at <pc> (b)
+delete <pc>            remove the breakpoint at <pc> (d)
+step [n]               execute one (or n) instructions (s)
+continue               run until a breakpoint, watchpoint or halt (c)
+reverse-step [n]       go back one (or n) instructions (rs)
+reverse-continue       go back to the last breakpoint, or the last instruction
+                       that changed a watched word (rc)
+regs                   print the registers and program counter (r)
+x <seg> <offset> <n>   print n words of a segment starting at offset
+watch <seg> <offset>   stop whenever the word at $m[seg][offset] changes (w)
+unwatch <seg> <offset> remove a watchpoint
+info                   list breakpoints and watchpoints (i)
+quit                   stop debugging (q)
+Numbers may be given in decimal or as 0x-prefixed hex.
+An empty line repeats the previous command.";
+
+/// Parse a single line of debugger input.
+///
+/// # Returns
+/// The parsed `Command`, or a message describing why it could not be parsed
+pub fn parse(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().ok_or("empty command")?;
+    let args = words
+        .map(parse_number)
+        .collect::<Result<Vec<u64>, String>>()?;
+
+    let command = match (name, args.as_slice()) {
+        ("break" | "b", &[pc]) => Command::Break(pc as usize),
+        ("delete" | "d", &[pc]) => Command::Delete(pc as usize),
+        ("step" | "s", &[]) => Command::Step(1),
+        ("step" | "s", &[count]) => Command::Step(count),
+        ("continue" | "c", &[]) => Command::Continue,
+        ("reverse-step" | "rs", &[]) => Command::ReverseStep(1),
+        ("reverse-step" | "rs", &[count]) => Command::ReverseStep(count),
+        ("reverse-continue" | "rc", &[]) => Command::ReverseContinue,
+        ("regs" | "r", &[]) => Command::Registers,
+        ("x", &[segment, offset, count]) => Command::Examine {
+            segment: segment as u32,
+            offset: offset as usize,
+            count: count as usize,
+        },
+        ("watch" | "w", &[segment, offset]) => Command::Watch {
+            segment: segment as u32,
+            offset: offset as usize,
+        },
+        ("unwatch", &[segment, offset]) => Command::Unwatch {
+            segment: segment as u32,
+            offset: offset as usize,
+        },
+        ("info" | "i", &[]) => Command::Info,
+        ("help" | "h", &[]) => Command::Help,
+        ("quit" | "q", &[]) => Command::Quit,
+        _ => {
+            return Err(format!(
+                "unrecognized command: {} (try `help`)",
+                line.trim()
+            ))
+        }
+    };
+    Ok(command)
+}
+
+fn parse_number(word: &str) -> Result<u64, String> {
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("not a number: {}", word))
+}
+
+/// A watched memory word and the value it held when last checked.
+/// `value` is `None` while the word is not addressable.
+struct Watchpoint {
+    segment: u32,
+    offset: usize,
+    value: Option<u32>,
+}
+
+/// Why execution stopped after a `step` or `continue`, or their reverses.
+enum Stop {
+    Done,
+    Breakpoint,
+    Watchpoint(u32, usize, Option<u32>, Option<u32>),
+    /// Reversing stopped at an instruction that changes a watched word.
+    Changes(u32, usize, Option<u32>, Option<u32>),
+    /// Reversing reached the first checkpoint.
+    Start,
+    Halted,
+    WaitingForInput,
+    Fault(Fault),
+}
+
+/// An interactive debugger wrapped around a `Machine`.
+///
+/// Commands are read line by line from `commands` and all debugger output is
+/// written to `out`, leaving the machine's own I/O untouched.
+///
+/// The debugger takes checkpoints of the machine as it runs, and reverses by
+/// restoring the last checkpoint before the state it wants and running
+/// forward from there. The machine's I/O goes through a `History`, so the
+/// program sees the same input as before and its output is not repeated.
+/// Only free-list memory maps the same segment IDs when run again, so
+/// reversing is refused with any other backend.
+pub struct Debugger<D: IoDevice, R: BufRead, W: Write, M: SegmentStore = rummem::Memory> {
+    machine: Machine<History<D>, M>,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    /// Earlier states of the machine, in order, starting with its state
+    /// when the debugger started.
+    checkpoints: Vec<Snapshot>,
+    /// Instructions between checkpoints.
+    interval: u64,
+    commands: R,
+    out: W,
+}
+
+impl<D: IoDevice, R: BufRead, W: Write, M: SegmentStore> Debugger<D, R, W, M> {
+    pub fn new(machine: Machine<D, M>, commands: R, out: W) -> Debugger<D, R, W, M> {
+        let machine = machine.map_io(History::new);
+        Debugger {
+            checkpoints: vec![Snapshot::capture(&machine)],
+            interval: CHECKPOINT_INTERVAL,
+            machine,
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            commands,
+            out,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine<History<D>, M> {
+        &self.machine
+    }
+
+    /// Run the read-eval-print loop until `quit` or the end of `commands`.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut previous: Option<Command> = None;
+        self.show_location()?;
+
+        loop {
+            write!(self.out, "(rum) ")?;
+            self.out.flush()?;
+
+            let mut line = String::new();
+            if self.commands.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let command = if line.trim().is_empty() {
+                match previous.clone() {
+                    Some(command) => command,
+                    None => continue,
+                }
+            } else {
+                match parse(&line) {
+                    Ok(command) => command,
+                    Err(message) => {
+                        writeln!(self.out, "{}", message)?;
+                        continue;
+                    }
+                }
+            };
+
+            if command == Command::Quit {
+                return Ok(());
+            }
+            self.dispatch(&command)?;
+            previous = Some(command);
+        }
+    }
+
+    fn dispatch(&mut self, command: &Command) -> io::Result<()> {
+        match *command {
+            Command::Break(pc) => {
+                self.breakpoints.insert(pc);
+                writeln!(self.out, "breakpoint at {}", pc)
+            }
+            Command::Delete(pc) => {
+                if !self.breakpoints.remove(&pc) {
+                    writeln!(self.out, "no breakpoint at {}", pc)?;
+                }
+                Ok(())
+            }
+            Command::Step(count) => {
+                let stop = self.resume(Some(count));
+                self.report(stop)
+            }
+            Command::Continue => {
+                let stop = self.resume(None);
+                self.report(stop)
+            }
+            Command::ReverseStep(_) | Command::ReverseContinue
+                if M::BACKEND != Backend::FreeList =>
+            {
+                writeln!(
+                    self.out,
+                    "cannot reverse with {} memory, which maps different segment IDs when run again",
+                    M::BACKEND.name()
+                )
+            }
+            Command::ReverseStep(count) => {
+                let stop = self.reverse(count);
+                self.report(stop)
+            }
+            Command::ReverseContinue => {
+                let stop = self.reverse_continue();
+                self.report(stop)
+            }
+            Command::Registers => self.show_registers(),
+            Command::Examine {
+                segment,
+                offset,
+                count,
+            } => self.examine(segment, offset, count),
+            Command::Watch { segment, offset } => {
+                let value = self.peek(segment, offset);
+                self.watchpoints.push(Watchpoint {
+                    segment,
+                    offset,
+                    value,
+                });
+                writeln!(
+                    self.out,
+                    "watching $m[{}][{}] = {}",
+                    segment,
+                    offset,
+                    show_word(value)
+                )
+            }
+            Command::Unwatch { segment, offset } => {
+                let before = self.watchpoints.len();
+                self.watchpoints
+                    .retain(|w| w.segment != segment || w.offset != offset);
+                if self.watchpoints.len() == before {
+                    writeln!(self.out, "no watchpoint on $m[{}][{}]", segment, offset)?;
+                }
+                Ok(())
+            }
+            Command::Info => {
+                for pc in &self.breakpoints {
+                    writeln!(self.out, "breakpoint at {}", pc)?;
+                }
+                for w in &self.watchpoints {
+                    writeln!(
+                        self.out,
+                        "watchpoint on $m[{}][{}] = {}",
+                        w.segment,
+                        w.offset,
+                        show_word(w.value)
+                    )?;
+                }
+                Ok(())
+            }
+            Command::Help => writeln!(self.out, "{}", HELP),
+            Command::Quit => Ok(()),
+        }
+    }
+
+    /// Execute up to `limit` instructions (or without limit), stopping early
+    /// at breakpoints, watchpoint changes or a halt. The instruction at the
+    /// current program counter is always executed, so resuming from a
+    /// breakpoint makes progress.
+    fn resume(&mut self, limit: Option<u64>) -> Stop {
+        let stop = self.resume_until(limit);
+        // Show the program's output so far before the prompt
+        let _ = self.machine.io_mut().flush();
+        stop
+    }
+
+    fn resume_until(&mut self, limit: Option<u64>) -> Stop {
+        if limit == Some(0) {
+            return Stop::Done;
+        }
+        let mut executed = 0;
+
+        loop {
+            match self.machine.step() {
+                Ok(Status::Running) => {}
+                Ok(Status::Halted) => return Stop::Halted,
+                Ok(Status::WaitingForInput) => return Stop::WaitingForInput,
+                Err(fault) => return Stop::Fault(fault),
+            }
+            executed += 1;
+            self.checkpoint();
+
+            for w in self.watchpoints.iter_mut() {
+                let value = peek(&self.machine, w.segment, w.offset);
+                if value != w.value {
+                    let old = w.value;
+                    w.value = value;
+                    return Stop::Watchpoint(w.segment, w.offset, old, value);
+                }
+            }
+
+            if limit == Some(executed) {
+                return Stop::Done;
+            }
+            if self.breakpoints.contains(&self.machine.program_counter()) {
+                return Stop::Breakpoint;
+            }
+        }
+    }
+
+    /// Take a checkpoint if the machine has run far enough past the last one.
+    fn checkpoint(&mut self) {
+        if M::BACKEND != Backend::FreeList {
+            return;
+        }
+        let last = self
+            .checkpoints
+            .last()
+            .map_or(0, |checkpoint| checkpoint.steps);
+        if self.machine.steps() < last + self.interval {
+            return;
+        }
+        self.checkpoints.push(Snapshot::capture(&self.machine));
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            // Keep the first checkpoint and every other one after it
+            let mut index = 0;
+            self.checkpoints.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            self.interval *= 2;
+        }
+    }
+
+    /// Go back `count` instructions, or as far as the first checkpoint.
+    fn reverse(&mut self, count: u64) -> Stop {
+        let start = self.checkpoints[0].steps;
+        let steps = self.machine.steps();
+        if count == 0 {
+            Stop::Done
+        } else if steps - start < count {
+            self.rewind(start);
+            Stop::Start
+        } else {
+            self.rewind(steps - count);
+            Stop::Done
+        }
+    }
+
+    /// Go back to the last state before the current one at a breakpoint, or
+    /// just before an instruction that changes a watched word, or else to
+    /// the first checkpoint.
+    fn reverse_continue(&mut self) -> Stop {
+        let steps = self.machine.steps();
+        self.machine.io_mut().reached(steps);
+
+        // Search back one checkpoint at a time, running forward from each
+        let mut end = steps;
+        for index in (0..self.checkpoints.len()).rev() {
+            if self.checkpoints[index].steps >= end {
+                continue;
+            }
+            self.checkpoints[index].restore_into(&mut self.machine);
+            let mut last = None;
+            while self.machine.steps() < end {
+                let step = self.machine.steps();
+                if self.breakpoints.contains(&self.machine.program_counter()) {
+                    last = Some((step, Stop::Breakpoint));
+                }
+                let before: Vec<Option<u32>> = self
+                    .watchpoints
+                    .iter()
+                    .map(|w| self.peek(w.segment, w.offset))
+                    .collect();
+                // With free-list memory and the recorded input, the machine
+                // repeats what it did before, so this only guards against
+                // looping forever on a step that does not complete
+                if self.machine.step() != Ok(Status::Running) {
+                    break;
+                }
+                for (w, old) in self.watchpoints.iter().zip(before) {
+                    let new = peek(&self.machine, w.segment, w.offset);
+                    if new != old {
+                        last = Some((step, Stop::Changes(w.segment, w.offset, old, new)));
+                    }
+                }
+            }
+            if let Some((step, stop)) = last {
+                self.rewind(step);
+                return stop;
+            }
+            end = self.checkpoints[index].steps;
+        }
+        self.rewind(self.checkpoints[0].steps);
+        Stop::Start
+    }
+
+    /// Return the machine to its state after `target` instructions, which
+    /// must not be before the first checkpoint.
+    fn rewind(&mut self, target: u64) {
+        let steps = self.machine.steps();
+        self.machine.io_mut().reached(steps);
+        let index = self
+            .checkpoints
+            .partition_point(|checkpoint| checkpoint.steps <= target);
+        self.checkpoints[index - 1].restore_into(&mut self.machine);
+        let _ = self.machine.run_for(target - self.machine.steps());
+        for w in self.watchpoints.iter_mut() {
+            w.value = peek(&self.machine, w.segment, w.offset);
+        }
+    }
+
+    fn report(&mut self, stop: Stop) -> io::Result<()> {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint => {
+                writeln!(self.out, "breakpoint at {}", self.machine.program_counter())?
+            }
+            Stop::Watchpoint(segment, offset, old, new) => writeln!(
+                self.out,
+                "$m[{}][{}] changed: {} -> {}",
+                segment,
+                offset,
+                show_word(old),
+                show_word(new)
+            )?,
+            Stop::Changes(segment, offset, old, new) => writeln!(
+                self.out,
+                "$m[{}][{}] changes here: {} -> {}",
+                segment,
+                offset,
+                show_word(old),
+                show_word(new)
+            )?,
+            Stop::Start => writeln!(self.out, "reached the start of the recorded history")?,
+            Stop::Halted => {
+                return writeln!(self.out, "machine halted");
+            }
+            Stop::WaitingForInput => writeln!(self.out, "waiting for input")?,
+            Stop::Fault(fault) => writeln!(self.out, "{}", fault)?,
+        }
+        self.show_location()
+    }
+
+    fn show_location(&mut self) -> io::Result<()> {
+        let pc = self.machine.program_counter();
+        match self.machine.current_instruction() {
+            Some(instruction) => writeln!(self.out, "[{}] {}", pc, instruction),
+            None => writeln!(self.out, "[{}] <outside segment 0>", pc),
+        }
+    }
+
+    fn show_registers(&mut self) -> io::Result<()> {
+        for (i, value) in self.machine.registers().iter().enumerate() {
+            writeln!(self.out, "r{} = 0x{:08x} ({})", i, value, value)?;
+        }
+        writeln!(self.out, "pc = {}", self.machine.program_counter())
+    }
+
+    fn examine(&mut self, segment: u32, offset: usize, count: usize) -> io::Result<()> {
+        let words = match self.machine.memory().segment(segment) {
+            Ok(words) => words,
+            Err(_) => return writeln!(self.out, "segment {} is not mapped", segment),
+        };
+        let end = offset.saturating_add(count).min(words.len());
+        if offset >= end {
+            return writeln!(
+                self.out,
+                "segment {} has {} words; nothing at offset {}",
+                segment,
+                words.len(),
+                offset
+            );
+        }
+        for (i, word) in words[offset..end].iter().enumerate() {
+            writeln!(self.out, "$m[{}][{}] = 0x{:08x}", segment, offset + i, word)?;
+        }
+        Ok(())
+    }
+
+    fn peek(&self, segment: u32, offset: usize) -> Option<u32> {
+        peek(&self.machine, segment, offset)
+    }
+}
+
+fn peek<D: IoDevice, M: SegmentStore>(
+    machine: &Machine<D, M>,
+    segment: u32,
+    offset: usize,
+) -> Option<u32> {
+    machine
+        .memory()
+        .segment(segment)
+        .ok()
+        .and_then(|words| words.get(offset))
+        .copied()
+}
+
+fn show_word(value: Option<u32>) -> String {
+    match value {
+        Some(value) => format!("0x{:08x}", value),
+        None => String::from("<unmapped>"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        rumio::Streams,
+        rummem::{BTreeMemory, FreeListMemory},
+    };
+
+    type TestMachine = Machine<Streams<&'static [u8], Vec<u8>>, FreeListMemory>;
+    type DebuggedMachine = Machine<History<Streams<&'static [u8], Vec<u8>>>, FreeListMemory>;
+
+    // 0: loadv r3, 4
+    // 1: loadv r4, 42
+    // 2: store r2, r3, r4
+    // 3: halt
+    // 4: (data)
+    const PROGRAM: [u32; 5] = [0xD600_0004, 0xD800_002A, 0x2000_009C, 0x7000_0000, 0];
+
+    fn session(script: &str) -> (String, DebuggedMachine) {
+        debug(PROGRAM.to_vec(), &b""[..], script)
+    }
+
+    fn machine(program: Vec<u32>, input: &'static [u8]) -> TestMachine {
+        let mut memory = FreeListMemory::new();
+        memory.load_program(program);
+        Machine::with_io(memory, Streams::new(input, Vec::new()))
+    }
+
+    fn debug(program: Vec<u32>, input: &'static [u8], script: &str) -> (String, DebuggedMachine) {
+        let machine = machine(program, input);
+        let mut out = Vec::new();
+        let mut debugger = Debugger::new(machine, script.as_bytes(), &mut out);
+        debugger.run().unwrap();
+        let Debugger { machine, .. } = debugger;
+        (String::from_utf8(out).unwrap(), machine)
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse("b 0x10"), Ok(Command::Break(16)));
+        assert_eq!(parse("step"), Ok(Command::Step(1)));
+        assert_eq!(parse("s 5"), Ok(Command::Step(5)));
+        assert_eq!(parse("reverse-step"), Ok(Command::ReverseStep(1)));
+        assert_eq!(parse("rs 3"), Ok(Command::ReverseStep(3)));
+        assert_eq!(parse("rc"), Ok(Command::ReverseContinue));
+        assert_eq!(
+            parse("x 0 2 4"),
+            Ok(Command::Examine {
+                segment: 0,
+                offset: 2,
+                count: 4
+            })
+        );
+        assert!(parse("break").is_err());
+        assert!(parse("frobnicate").is_err());
+        assert!(parse("b twelve").is_err());
+    }
+
+    #[test]
+    fn breakpoint_and_step() {
+        let (out, machine) = session("break 1\ncontinue\nstep\n\nquit\n");
+        assert!(out.contains("breakpoint at 1\n[1] loadv r4, 42"));
+        assert!(out.contains("[2] store r2, r3, r4"));
+        assert!(out.contains("[3] halt"));
+        assert_eq!(machine.program_counter(), 3);
+    }
+
+    #[test]
+    fn watchpoint_fires_on_store() {
+        let (out, machine) = session("watch 0 0x4\nc\nregs\nq\n");
+        assert!(out.contains("$m[0][4] changed: 0x00000000 -> 0x0000002a"));
+        assert!(out.contains("r4 = 0x0000002a (42)"));
+        assert_eq!(machine.program_counter(), 3);
+        assert!(!machine.is_halted());
+    }
+
+    #[test]
+    fn fault_stops_at_faulting_instruction() {
+        // loadv r1, 1; unmap r1
+        let machine = machine(vec![0xD200_0001, 0x9000_0001], &[]);
+        let mut out = Vec::new();
+        Debugger::new(machine, "c\nregs\n".as_bytes(), &mut out)
+            .run()
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("machine fault at pc 1: segment 1 is not mapped"));
+        assert!(out.contains("[1] unmap r1"));
+        assert!(out.contains("pc = 1"));
+    }
+
+    #[test]
+    fn reverse_step_restores_registers() {
+        let (out, machine) = session("s 2\nrs\nr\nrs 5\n");
+        assert!(out.contains("[2] store r2, r3, r4\n(rum) [1] loadv r4, 42"));
+        assert!(out.contains("r3 = 0x00000004 (4)\nr4 = 0x00000000 (0)"));
+        assert!(out.contains("reached the start of the recorded history\n[0]"));
+        assert_eq!(machine.program_counter(), 0);
+        assert_eq!(machine.steps(), 0);
+    }
+
+    #[test]
+    fn reverse_continue_finds_changes() {
+        let (out, machine) = session("watch 0 4\nc\nc\nrc\nx 0 4 1\nrc\n");
+        assert!(out.contains("machine halted"));
+        assert!(
+            out.contains("$m[0][4] changes here: 0x00000000 -> 0x0000002a\n[2] store r2, r3, r4")
+        );
+        assert!(out.contains("$m[0][4] = 0x00000000"));
+        assert!(out.contains("reached the start of the recorded history"));
+        assert_eq!(machine.program_counter(), 0);
+        assert!(!machine.is_halted());
+
+        let (out, machine) = session("b 1\nc\nc\nrc\n");
+        assert!(out.contains("machine halted\n(rum) breakpoint at 1\n[1] loadv r4, 42"));
+        assert_eq!(machine.steps(), 1);
+    }
+
+    #[test]
+    fn reversing_replays_io() {
+        // in r1; out r1; in r1; out r1; halt
+        let program = vec![
+            0xB000_0001,
+            0xA000_0001,
+            0xB000_0001,
+            0xA000_0001,
+            0x7000_0000,
+        ];
+        let (_, machine) = debug(program, b"ab", "s 2\nrs 2\nc\n");
+        assert!(machine.is_halted());
+        assert_eq!(machine.registers()[1], b'b' as u32);
+        assert_eq!(machine.into_io().inner().output, b"ab");
+    }
+
+    /// A reader over input that several readers take turns to consume.
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<&'static [u8]>>);
+
+    impl Read for Shared {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.borrow_mut().read(buf)
+        }
+    }
+
+    #[test]
+    fn commands_share_input_with_program() {
+        // in r1; out r1; halt, with the input after the first command
+        let input = Shared(Rc::new(RefCell::new(&b"s\nZc\n"[..])));
+        let mut memory = FreeListMemory::new();
+        memory.load_program(vec![0xB000_0001, 0xA000_0001, 0x7000_0000]);
+        let machine = Machine::with_io(memory, Streams::new(input.clone(), Vec::new()));
+        let mut out = Vec::new();
+        let mut debugger = Debugger::new(machine, commands(input), &mut out);
+        debugger.run().unwrap();
+        assert!(debugger.machine().is_halted());
+        assert_eq!(debugger.machine().io().inner().output, b"Z");
+    }
+
+    #[test]
+    fn refuses_reversing_btree_memory() {
+        let mut memory = BTreeMemory::new();
+        memory.load_program(PROGRAM.to_vec());
+        let machine = Machine::with_io(memory, Streams::new(&b""[..], Vec::new()));
+        let mut out = Vec::new();
+        let mut debugger = Debugger::new(machine, "s 2\nrs\nrc\n".as_bytes(), &mut out);
+        debugger.run().unwrap();
+        assert_eq!(debugger.machine().steps(), 2);
+        assert_eq!(debugger.checkpoints.len(), 1);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("cannot reverse with btree memory").count(), 2);
+    }
+
+    #[test]
+    fn checkpoints_stay_bounded() {
+        // 0: loadv r2, 1
+        // 1: add r1, r1, r2
+        // 2: loadv r3, 1
+        // 3: loadp r0, r3 (jump to 1)
+        let program = vec![0xD400_0001, 0x3000_004A, 0xD600_0001, 0xC000_0003];
+        let machine = machine(program, &[]);
+        let mut out = Vec::new();
+        let mut debugger = Debugger::new(machine, "s 1000000\nrs 499999\n".as_bytes(), &mut out);
+        debugger.run().unwrap();
+        assert!(debugger.checkpoints.len() <= MAX_CHECKPOINTS);
+        assert!(debugger.interval > CHECKPOINT_INTERVAL);
+
+        // One step to set up, then three per iteration
+        assert_eq!(debugger.machine().steps(), 500_001);
+        assert_eq!(debugger.machine().registers()[1], 166_667);
+        assert_eq!(debugger.machine().program_counter(), 3);
+    }
+
+    #[test]
+    fn examine_and_halt() {
+        let (out, machine) = session("x 1 0 1\nx 0 2 10\nc\n");
+        assert!(out.contains("segment 1 is not mapped"));
+        assert!(out.contains("$m[0][3] = 0x70000000"));
+        assert!(out.contains("$m[0][4] = 0x00000000"));
+        assert!(!out.contains("$m[0][5]"));
+        assert!(out.contains("machine halted"));
+        assert!(machine.is_halted());
+    }
+}
@@ -0,0 +1,91 @@
+use std::{collections::HashMap, io};
+
+use crate::rumio::IoDevice;
+
+/// An I/O device that remembers the input a machine has read, so that the
+/// debugger can run the machine again from an earlier state and see the same
+/// input, without repeating output the program has already written.
+///
+/// I/O is keyed by the number of instructions executed before it. Below the
+/// furthest point the machine has reached, Input gets the recorded bytes and
+/// Output is discarded; beyond it, both go to the wrapped device.
+pub struct History<D: IoDevice> {
+    io: D,
+    /// The result of each Input executed so far, by step.
+    inputs: HashMap<u64, Option<u8>>,
+    /// Steps before this have already been executed once.
+    frontier: u64,
+}
+
+impl<D: IoDevice> History<D> {
+    pub fn new(io: D) -> History<D> {
+        History {
+            io,
+            inputs: HashMap::new(),
+            frontier: 0,
+        }
+    }
+
+    /// Get the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.io
+    }
+
+    /// Record that the first `steps` instructions have been executed, before
+    /// the machine is returned to an earlier state.
+    pub(super) fn reached(&mut self, steps: u64) {
+        self.frontier = self.frontier.max(steps);
+    }
+}
+
+impl<D: IoDevice> IoDevice for History<D> {
+    fn output(&mut self, value: u8) -> io::Result<()> {
+        self.io.output(value)
+    }
+
+    fn input(&mut self) -> io::Result<Option<u8>> {
+        self.io.input()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+
+    fn output_at(&mut self, step: u64, value: u8) -> io::Result<()> {
+        if step < self.frontier {
+            return Ok(());
+        }
+        self.io.output_at(step, value)
+    }
+
+    fn input_at(&mut self, step: u64) -> io::Result<Option<u8>> {
+        if let Some(&input) = self.inputs.get(&step) {
+            return Ok(input);
+        }
+        let input = self.io.input_at(step)?;
+        self.inputs.insert(step, input);
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rumio::Streams;
+
+    #[test]
+    fn replays_before_frontier() {
+        let mut history = History::new(Streams::new(&b"ab"[..], Vec::new()));
+        assert_eq!(history.input_at(3).unwrap(), Some(b'a'));
+        history.output_at(4, b'x').unwrap();
+        history.reached(5);
+
+        // Running steps 3 and 4 again reads the same input and writes nothing
+        assert_eq!(history.input_at(3).unwrap(), Some(b'a'));
+        history.output_at(4, b'y').unwrap();
+        assert_eq!(history.input_at(5).unwrap(), Some(b'b'));
+        assert_eq!(history.input_at(6).unwrap(), None);
+        history.output_at(6, b'z').unwrap();
+        assert_eq!(history.inner().output, b"xz");
+    }
+}
@@ -0,0 +1,476 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    rumdis::{self, Operation},
+    rumerr::{Fault, UmError},
+    rumio::Streams,
+    rummem::SegmentStore,
+    rumrun::{Engine, Machine, Outcome, Registers, Status},
+};
+
+/// A machine whose output is captured for comparison.
+type Captured<'a, M> = Machine<Streams<&'a [u8], Vec<u8>>, M>;
+
+/// Two machines running the same program with different memory backends,
+/// one instruction at a time, checked against each other after every step.
+///
+/// Backends are free to choose different segment IDs, so a register (or
+/// fault) that holds a segment ID on the left is matched with the ID that
+/// the right backend gave the same segment.
+pub struct Lockstep<'a, A: SegmentStore, B: SegmentStore> {
+    left: Captured<'a, A>,
+    right: Captured<'a, B>,
+    /// Each segment ID handed out on the left, and the right's ID for the same segment.
+    ids: HashMap<u32, u32>,
+    /// Bytes of output already compared.
+    compared: usize,
+}
+
+/// How a run ended with both backends in agreement.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Agreement {
+    pub steps: u64,
+    pub outcome: Result<Outcome, Fault>,
+}
+
+/// The first instruction after which the two machines differed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Divergence {
+    /// The number of instructions executed before it.
+    pub step: u64,
+    pub program_counter: usize,
+    /// The instruction word, if the program counter pointed at one.
+    pub instruction: Option<u32>,
+    pub difference: Difference,
+}
+
+/// What differed between the left and right machines after an instruction.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Difference {
+    /// One machine faulted, halted or faulted differently.
+    Outcome(Result<Status, Fault>, Result<Status, Fault>),
+    ProgramCounter(usize, usize),
+    Registers(Registers, Registers),
+    /// The output written by the instruction.
+    Output(Vec<u8>, Vec<u8>),
+    /// The number of instructions executed.
+    Steps(u64, u64),
+}
+
+impl<'a, A: SegmentStore, B: SegmentStore> Lockstep<'a, A, B> {
+    /// # Arguments
+    /// - `left`, `right`: empty memory of the backends to compare
+    /// - `program`: the program to load into both
+    /// - `input`: the input given to each machine
+    pub fn new(mut left: A, mut right: B, program: &[u32], input: &'a [u8]) -> Lockstep<'a, A, B> {
+        left.load_program(program.to_vec());
+        right.load_program(program.to_vec());
+        Lockstep {
+            left: Machine::with_io(left, Streams::new(input, Vec::new())),
+            right: Machine::with_io(right, Streams::new(input, Vec::new())),
+            ids: HashMap::from([(0, 0)]),
+            compared: 0,
+        }
+    }
+
+    /// Get the output both machines have agreed on so far.
+    pub fn output(&self) -> &[u8] {
+        &self.left.io().output[..self.compared]
+    }
+
+    /// Execute one instruction on both machines and compare them.
+    ///
+    /// # Returns
+    /// The result of the instruction, which both machines agree on
+    ///
+    /// # Errors
+    /// A `Divergence` if the machines differ after the instruction
+    pub fn step(&mut self) -> Result<Result<Status, Fault>, Box<Divergence>> {
+        let step = self.left.steps();
+        let program_counter = self.left.program_counter();
+        let instruction = self.left.current_word();
+        let diverged = |difference| {
+            Box::new(Divergence {
+                step,
+                program_counter,
+                instruction,
+                difference,
+            })
+        };
+
+        let left = self.left.step();
+        let right = self.right.step();
+        let agreed = match (&left, &right) {
+            (Ok(l), Ok(r)) => l == r,
+            (Err(l), Err(r)) => self.same_fault(l, r),
+            _ => false,
+        };
+        if !agreed {
+            return Err(diverged(Difference::Outcome(left, right)));
+        }
+
+        let decoded = instruction.map(rumdis::decode);
+        if let Some(rumdis::Decoded {
+            operation: Some(Operation::Map),
+            b,
+            ..
+        }) = decoded
+        {
+            if left.is_ok() {
+                let b = b as usize;
+                self.ids
+                    .insert(self.left.registers()[b], self.right.registers()[b]);
+            }
+        }
+
+        if self.left.program_counter() != self.right.program_counter() {
+            return Err(diverged(Difference::ProgramCounter(
+                self.left.program_counter(),
+                self.right.program_counter(),
+            )));
+        }
+        let registers = self
+            .left
+            .registers()
+            .iter()
+            .zip(self.right.registers())
+            .all(|(&l, &r)| l == r || self.ids.get(&l) == Some(&r));
+        if !registers {
+            return Err(diverged(Difference::Registers(
+                *self.left.registers(),
+                *self.right.registers(),
+            )));
+        }
+        let (l, r) = (&self.left.io().output, &self.right.io().output);
+        if l[self.compared..] != r[self.compared..] {
+            return Err(diverged(Difference::Output(
+                l[self.compared..].to_vec(),
+                r[self.compared..].to_vec(),
+            )));
+        }
+        self.compared = l.len();
+        Ok(left)
+    }
+
+    /// Run both machines until they halt or fault together, or diverge.
+    ///
+    /// # Arguments
+    /// - `budget`: the most instructions to execute, or `None` for no limit
+    ///
+    /// # Errors
+    /// The first `Divergence` between the machines
+    pub fn run(&mut self, budget: Option<u64>) -> Result<Agreement, Box<Divergence>> {
+        let mut remaining = budget;
+        let outcome = loop {
+            if remaining == Some(0) {
+                break Ok(Outcome::Yielded);
+            }
+            match self.step()? {
+                Ok(Status::Running) => {}
+                Ok(Status::Halted) => break Ok(Outcome::Halted),
+                Ok(Status::WaitingForInput) => break Ok(Outcome::WaitingForInput),
+                Err(fault) => break Err(fault),
+            }
+            remaining = remaining.map(|n| n - 1);
+        };
+        Ok(Agreement {
+            steps: self.left.steps(),
+            outcome,
+        })
+    }
+
+    /// Check whether two faults are the same, allowing for different segment IDs.
+    fn same_fault(&self, left: &Fault, right: &Fault) -> bool {
+        let id = |id: u32| self.ids.get(&id).copied().unwrap_or(id);
+        let error = match left.error {
+            UmError::UnmappedSegment(segment) => UmError::UnmappedSegment(id(segment)),
+            UmError::OutOfBounds {
+                segment,
+                offset,
+                length,
+            } => UmError::OutOfBounds {
+                segment: id(segment),
+                offset,
+                length,
+            },
+            ref error => error.clone(),
+        };
+        error == right.error
+            && left.program_counter == right.program_counter
+            && left.instruction == right.instruction
+    }
+}
+
+/// Run a program on the plain interpreter (left) and the fused engine
+/// (right), comparing the machines every `interval` instructions.
+///
+/// The fused engine executes several instructions at once, so a
+/// `Divergence` gives the state at the start of the interval in which the
+/// machines came to differ, rather than the exact instruction.
+///
+/// # Arguments
+/// - `plain`, `fused`: empty memory for each machine, of a backend that
+///   hands out segment IDs deterministically (not `BTreeMemory`)
+/// - `program`: the program to load into both
+/// - `input`: the input given to each machine
+/// - `budget`: the most instructions to execute, or `None` for no limit
+/// - `interval`: the number of instructions between comparisons
+///
+/// # Errors
+/// The first interval after which the machines differ
+pub fn compare_engines<M: SegmentStore>(
+    mut plain: M,
+    mut fused: M,
+    program: &[u32],
+    input: &[u8],
+    budget: Option<u64>,
+    interval: u64,
+) -> Result<Agreement, Box<Divergence>> {
+    plain.load_program(program.to_vec());
+    fused.load_program(program.to_vec());
+    let mut plain = Machine::with_io(plain, Streams::new(input, Vec::new()));
+    let mut fused = Machine::with_io(fused, Streams::new(input, Vec::new()));
+    fused.set_engine(Engine::Fused);
+
+    let mut remaining = budget;
+    let mut compared = 0;
+    loop {
+        let slice = remaining.map_or(interval, |n| n.min(interval));
+        let step = plain.steps();
+        let program_counter = plain.program_counter();
+        let instruction = plain.current_word();
+
+        let left = plain.run_for(slice);
+        let right = fused.run_for(slice);
+        let (l, r) = (&plain.io().output, &fused.io().output);
+        let difference = if left != right {
+            Some(Difference::Outcome(status(&left), status(&right)))
+        } else if plain.program_counter() != fused.program_counter() {
+            Some(Difference::ProgramCounter(
+                plain.program_counter(),
+                fused.program_counter(),
+            ))
+        } else if plain.registers() != fused.registers() {
+            Some(Difference::Registers(
+                *plain.registers(),
+                *fused.registers(),
+            ))
+        } else if l[compared..] != r[compared..] {
+            Some(Difference::Output(
+                l[compared..].to_vec(),
+                r[compared..].to_vec(),
+            ))
+        } else if plain.steps() != fused.steps() {
+            Some(Difference::Steps(plain.steps(), fused.steps()))
+        } else {
+            None
+        };
+        if let Some(difference) = difference {
+            return Err(Box::new(Divergence {
+                step,
+                program_counter,
+                instruction,
+                difference,
+            }));
+        }
+        compared = l.len();
+
+        remaining = remaining.map(|n| n - slice);
+        match left {
+            Ok(Outcome::Yielded) if remaining != Some(0) => {}
+            outcome => {
+                return Ok(Agreement {
+                    steps: plain.steps(),
+                    outcome,
+                })
+            }
+        }
+    }
+}
+
+/// Express how `run_for` returned as the status of the last instruction.
+fn status(outcome: &Result<Outcome, Fault>) -> Result<Status, Fault> {
+    match outcome {
+        Ok(Outcome::Halted) => Ok(Status::Halted),
+        Ok(Outcome::Yielded) => Ok(Status::Running),
+        Ok(Outcome::WaitingForInput) => Ok(Status::WaitingForInput),
+        Err(fault) => Err(fault.clone()),
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let outcome = |f: &mut fmt::Formatter<'_>, outcome: &Result<Status, Fault>| match outcome {
+            Ok(status) => write!(f, "{:?}", status),
+            Err(fault) => write!(f, "{}", fault.error),
+        };
+        match self {
+            Difference::Outcome(left, right) => {
+                write!(f, "outcome ")?;
+                outcome(f, left)?;
+                write!(f, " vs ")?;
+                outcome(f, right)
+            }
+            Difference::ProgramCounter(left, right) => {
+                write!(f, "program counter {} vs {}", left, right)
+            }
+            Difference::Registers(left, right) => {
+                write!(f, "registers {:08x?} vs {:08x?}", left, right)
+            }
+            Difference::Output(left, right) => write!(
+                f,
+                "output {:?} vs {:?}",
+                String::from_utf8_lossy(left),
+                String::from_utf8_lossy(right)
+            ),
+            Difference::Steps(left, right) => write!(f, "steps {} vs {}", left, right),
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "diverged at step {}, pc {}",
+            self.step, self.program_counter
+        )?;
+        if let Some(word) = self.instruction {
+            write!(f, " ({})", rumdis::disassemble(&word))?;
+        }
+        write!(f, ": {}", self.difference)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        rumgen,
+        rummem::{BTreeMemory, FreeListMemory},
+    };
+
+    // 0: loadv r1, 3
+    // 1: map r2, r1
+    // 2: map r3, r1
+    // 3: loadv r4, 'k'
+    // 4: store r3, r0, r4
+    // 5: load r5, r3, r0
+    // 6: output r5
+    // 7: unmap r2
+    // 8: map r2, r1
+    // 9: halt
+    const SEGMENTS: [u32; 10] = [
+        0xD200_0003,
+        0x8000_0011,
+        0x8000_0019,
+        0xD800_006B,
+        0x2000_00C4,
+        0x1000_0158,
+        0xA000_0005,
+        0x9000_0002,
+        0x8000_0011,
+        0x7000_0000,
+    ];
+
+    #[test]
+    fn backends_agree() {
+        let mut lockstep = Lockstep::new(
+            BTreeMemory::new(),
+            FreeListMemory::checked(),
+            &SEGMENTS,
+            b"",
+        );
+        assert_eq!(
+            lockstep.run(None),
+            Ok(Agreement {
+                steps: 10,
+                outcome: Ok(Outcome::Halted)
+            })
+        );
+        assert_eq!(lockstep.output(), b"k");
+    }
+
+    #[test]
+    fn detects_divergence() {
+        // Use a segment after unmapping it, which only checked memory reports as unmapped
+        let mut program = SEGMENTS[..8].to_vec();
+        program.extend([0x1000_0150, 0x7000_0000]); // load r5, r2, r0; halt
+        let mut lockstep = Lockstep::new(
+            FreeListMemory::new(),
+            FreeListMemory::checked(),
+            &program,
+            b"",
+        );
+        let divergence = lockstep.run(None).unwrap_err();
+        assert_eq!((divergence.step, divergence.program_counter), (8, 8));
+        assert!(matches!(
+            divergence.difference,
+            Difference::Outcome(
+                Err(Fault {
+                    error: UmError::OutOfBounds { .. },
+                    ..
+                }),
+                Err(Fault {
+                    error: UmError::UnmappedSegment(1),
+                    ..
+                })
+            )
+        ));
+        assert_eq!(lockstep.output(), b"k");
+    }
+
+    #[test]
+    fn random_programs_agree() {
+        for seed in 0..200 {
+            let program = rumgen::program(&mut StdRng::seed_from_u64(seed), 64);
+            let mut lockstep = Lockstep::new(
+                BTreeMemory::new(),
+                FreeListMemory::checked(),
+                &program,
+                b"input",
+            );
+            match lockstep.run(None) {
+                Ok(agreement) => assert_eq!(agreement.outcome, Ok(Outcome::Halted)),
+                Err(divergence) => panic!("seed {}: {}", seed, divergence),
+            }
+        }
+    }
+
+    #[test]
+    fn engines_agree() {
+        let agreement = compare_engines(
+            FreeListMemory::new(),
+            FreeListMemory::new(),
+            &SEGMENTS,
+            b"",
+            None,
+            3,
+        );
+        assert_eq!(
+            agreement,
+            Ok(Agreement {
+                steps: 10,
+                outcome: Ok(Outcome::Halted)
+            })
+        );
+
+        for seed in 0..50 {
+            let program = rumgen::program(&mut StdRng::seed_from_u64(seed), 64);
+            let result = compare_engines(
+                FreeListMemory::checked(),
+                FreeListMemory::checked(),
+                &program,
+                b"input",
+                Some(100),
+                7,
+            );
+            match result {
+                Ok(agreement) => assert!(agreement.steps <= 100),
+                Err(divergence) => panic!("seed {}: {}", seed, divergence),
+            }
+        }
+    }
+}
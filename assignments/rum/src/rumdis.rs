@@ -1,3 +1,54 @@
+use std::fmt;
+
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+/// The fourteen operations understood by the Universal Machine, in opcode order.
+#[derive(FromPrimitive, Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u32)]
+pub enum Operation {
+    ConditionalMove,
+    LoadSegment,
+    StoreSegment,
+    Add,
+    Multiply,
+    Divide,
+    Nand,
+    Halt,
+    Map,
+    Unmap,
+    Output,
+    Input,
+    LoadProgram,
+    LoadValue,
+}
+
+/// Mnemonics for each operation, indexed by opcode.
+/// These match the encoder names used by `rumasm`.
+const MNEMONICS: [&str; 14] = [
+    "cmov", "load", "store", "add", "mult", "div", "nand", "halt", "map", "unmap", "output",
+    "input", "loadp", "loadv",
+];
+
+impl Operation {
+    /// Get the operation with the given opcode, if it is valid.
+    pub fn from_opcode(opcode: u32) -> Option<Operation> {
+        FromPrimitive::from_u32(opcode)
+    }
+
+    /// Get the operation with the given mnemonic (e.g. `add`), if there is one.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Operation> {
+        MNEMONICS
+            .iter()
+            .position(|&m| m == mnemonic)
+            .and_then(|opcode| Operation::from_opcode(opcode as u32))
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        MNEMONICS[self as usize]
+    }
+}
+
 #[derive(Default, Debug)]
 /// A Universal Machine instruction.
 /// Not all fields will be used for a given instruction.
@@ -10,6 +61,75 @@ pub struct Instruction {
     pub load_value: u32,
 }
 
+impl Instruction {
+    /// Get the operation named by this instruction's opcode, if it is valid.
+    pub fn operation(&self) -> Option<Operation> {
+        Operation::from_opcode(self.opcode)
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Format the instruction as a short mnemonic, e.g. `add r1, r2, r3`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (a, b, c) = (self.reg_a, self.reg_b, self.reg_c);
+        let operation = match self.operation() {
+            Some(operation) => operation,
+            None => return write!(f, "invalid (opcode {})", self.opcode),
+        };
+        let mnemonic = operation.mnemonic();
+        match operation {
+            Operation::Halt => write!(f, "{}", mnemonic),
+            Operation::Map | Operation::LoadProgram => write!(f, "{} r{}, r{}", mnemonic, b, c),
+            Operation::Unmap | Operation::Output | Operation::Input => {
+                write!(f, "{} r{}", mnemonic, c)
+            }
+            Operation::LoadValue => {
+                write!(f, "{} r{}, {}", mnemonic, self.reg_load, self.load_value)
+            }
+            _ => write!(f, "{} r{}, r{}, r{}", mnemonic, a, b, c),
+        }
+    }
+}
+
+/// An instruction decoded once for fast execution, rather than for display.
+/// For LoadValue, `a` holds the destination register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decoded {
+    pub operation: Option<Operation>,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub value: u32,
+}
+
+/// Decode a binary word for execution.
+///
+/// # Arguments
+/// - `word`: a `u32` word
+///
+/// # Returns
+/// A `Decoded` instruction; `operation` is `None` for an invalid opcode
+pub fn decode(word: u32) -> Decoded {
+    let operation = Operation::from_opcode(word >> 28);
+    if operation == Some(Operation::LoadValue) {
+        Decoded {
+            operation,
+            a: (word >> 25 & 0b111) as u8,
+            b: 0,
+            c: 0,
+            value: word & 0x01FF_FFFF,
+        }
+    } else {
+        Decoded {
+            operation,
+            a: (word >> 6 & 0b111) as u8,
+            b: (word >> 3 & 0b111) as u8,
+            c: (word & 0b111) as u8,
+            value: 0,
+        }
+    }
+}
+
 const WORD_SIZE: u8 = 32;
 
 /// Disassemble a binary word into a Universal Machine instruction.
@@ -47,8 +167,10 @@ fn get_bits(word: &u32, lsb: u8, width: u8) -> u32 {
 }
 
 #[cfg(test)]
+// Binary literals are grouped by instruction field, not by nibble
+#[allow(clippy::unusual_byte_groupings)]
 mod tests {
-    use super::disassemble;
+    use super::{decode, disassemble, Operation};
 
     #[test]
     fn segmented_load() {
@@ -72,4 +194,48 @@ mod tests {
         assert_eq!(product.reg_load, 7);
         assert_eq!(product.load_value, 20228433);
     }
+
+    #[test]
+    fn display() {
+        let word: u32 = 0b0011_0000000000000000000_001_010_011;
+        let product = disassemble(&word);
+
+        assert_eq!(product.operation(), Some(Operation::Add));
+        assert_eq!(product.to_string(), "add r1, r2, r3");
+        assert_eq!(disassemble(&0xF000_0000).to_string(), "invalid (opcode 15)");
+        assert_eq!(disassemble(&0xD200_002A).to_string(), "loadv r1, 42");
+        assert_eq!(disassemble(&0xC000_0001).to_string(), "loadp r0, r1");
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(
+            Operation::from_mnemonic("loadp"),
+            Some(Operation::LoadProgram)
+        );
+        assert_eq!(Operation::from_mnemonic("jump"), None);
+        assert_eq!(Operation::Nand.mnemonic(), "nand");
+    }
+
+    #[test]
+    fn decode_matches_disassemble() {
+        for word in [
+            0b0001_0000000000000000000_110_010_101,
+            0b1101_111_1001101001010100101010001,
+            0xC000_0001,
+            0xF000_0000,
+        ] {
+            let slow = disassemble(&word);
+            let fast = decode(word);
+            assert_eq!(fast.operation, slow.operation());
+            if fast.operation == Some(Operation::LoadValue) {
+                assert_eq!(fast.a as u32, slow.reg_load);
+                assert_eq!(fast.value, slow.load_value);
+            } else {
+                assert_eq!(fast.a as u32, slow.reg_a);
+                assert_eq!(fast.b as u32, slow.reg_b);
+                assert_eq!(fast.c as u32, slow.reg_c);
+            }
+        }
+    }
 }
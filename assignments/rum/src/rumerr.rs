@@ -0,0 +1,253 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Write},
+};
+
+use serde::Serialize;
+
+use crate::{
+    rumdis,
+    rummem::{Limit, Usage},
+};
+
+/// A condition under which the Universal Machine fails.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum UmError {
+    /// The opcode field does not name one of the fourteen operations.
+    InvalidOpcode(u32),
+    /// A Divide instruction had zero in register C.
+    DivideByZero,
+    /// A segment was used (or unmapped) while not mapped.
+    UnmappedSegment(u32),
+    /// An Unmap instruction named segment 0, which holds the program.
+    UnmapProgram,
+    /// An offset was past the end of a mapped segment.
+    OutOfBounds {
+        segment: u32,
+        offset: u32,
+        length: usize,
+    },
+    /// The program counter does not point inside segment 0.
+    PcOutOfRange { length: usize },
+    /// A new segment could not be allocated.
+    OutOfMemory { length: u32 },
+    /// A new segment of `length` words would exceed a limit set with `rummem::Limited`.
+    LimitExceeded {
+        limit: Limit,
+        length: u32,
+        /// The memory mapped before the attempt.
+        usage: Usage,
+    },
+    /// An Input instruction found no more input (only in strict mode, see
+    /// `Machine::set_strict_eof`).
+    InputEof,
+}
+
+impl UmError {
+    /// A short name for the kind of error, e.g. `divide_by_zero`, that stays
+    /// the same whatever the details.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UmError::InvalidOpcode(_) => "invalid_opcode",
+            UmError::DivideByZero => "divide_by_zero",
+            UmError::UnmappedSegment(_) => "unmapped_segment",
+            UmError::UnmapProgram => "unmap_program",
+            UmError::OutOfBounds { .. } => "out_of_bounds",
+            UmError::PcOutOfRange { .. } => "pc_out_of_range",
+            UmError::OutOfMemory { .. } => "out_of_memory",
+            UmError::LimitExceeded { .. } => "limit_exceeded",
+            UmError::InputEof => "input_eof",
+        }
+    }
+}
+
+impl fmt::Display for UmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UmError::InvalidOpcode(opcode) => write!(f, "invalid opcode {}", opcode),
+            UmError::DivideByZero => write!(f, "division by zero"),
+            UmError::UnmappedSegment(segment) => write!(f, "segment {} is not mapped", segment),
+            UmError::UnmapProgram => {
+                write!(f, "segment 0 holds the program and cannot be unmapped")
+            }
+            UmError::OutOfBounds {
+                segment,
+                offset,
+                length,
+            } => write!(
+                f,
+                "offset {} is out of bounds for segment {} of length {}",
+                offset, segment, length
+            ),
+            UmError::PcOutOfRange { length } => {
+                write!(
+                    f,
+                    "program counter is outside segment 0 of length {}",
+                    length
+                )
+            }
+            UmError::OutOfMemory { length } => {
+                write!(f, "out of memory mapping a segment of {} words", length)
+            }
+            UmError::LimitExceeded {
+                limit,
+                length,
+                usage,
+            } => write!(
+                f,
+                "mapping a segment of {} words would exceed the {} ({})",
+                length, limit, usage
+            ),
+            UmError::InputEof => write!(f, "end of input"),
+        }
+    }
+}
+
+impl Error for UmError {}
+
+/// A machine failure, along with where it happened.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Fault {
+    pub error: UmError,
+    pub program_counter: usize,
+    /// The faulting instruction word, if the program counter pointed at one.
+    pub instruction: Option<u32>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "machine fault at pc {}: {}",
+            self.program_counter, self.error
+        )?;
+        if let Some(word) = self.instruction {
+            write!(
+                f,
+                " (instruction 0x{:08x}: {})",
+                word,
+                rumdis::disassemble(&word)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Fault {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A fault and the state of the machine when it happened, for reporting to
+/// other programs (`rum --fault-report json`).
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct FaultReport {
+    /// The `UmError::kind` of the fault.
+    pub kind: &'static str,
+    /// The fault as `rum` describes it to people.
+    pub message: String,
+    pub pc: usize,
+    /// The faulting instruction, if the program counter pointed at one.
+    pub instruction: Option<InstructionReport>,
+    pub registers: [u32; 8],
+    /// Segments mapped when the fault happened, including segment 0.
+    pub segments: usize,
+    /// Instructions completed before the faulting one.
+    pub instructions: u64,
+}
+
+/// A faulting instruction, as a word and decoded.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct InstructionReport {
+    pub word: u32,
+    /// The operation's `rumdis` mnemonic, or `None` for an invalid opcode.
+    pub operation: Option<&'static str>,
+    /// The instruction as disassembled by `rumdis`.
+    pub text: String,
+}
+
+impl FaultReport {
+    /// Describe `fault` along with the machine state it left behind.
+    ///
+    /// # Arguments
+    /// - `fault`: the fault
+    /// - `registers`: the registers after the fault
+    /// - `segments`: the number of segments mapped
+    /// - `instructions`: the number of instructions completed
+    pub fn new(
+        fault: &Fault,
+        registers: [u32; 8],
+        segments: usize,
+        instructions: u64,
+    ) -> FaultReport {
+        let instruction = fault.instruction.map(|word| {
+            let instruction = rumdis::disassemble(&word);
+            InstructionReport {
+                word,
+                operation: instruction
+                    .operation()
+                    .map(|operation| operation.mnemonic()),
+                text: instruction.to_string(),
+            }
+        });
+        FaultReport {
+            kind: fault.error.kind(),
+            message: fault.error.to_string(),
+            pc: fault.program_counter,
+            instruction,
+            registers,
+            segments,
+            instructions,
+        }
+    }
+
+    /// Write the report as a single line of JSON.
+    pub fn write_json(&self, mut out: impl Write) -> io::Result<()> {
+        serde_json::to_writer(&mut out, self)?;
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_faults_as_json() {
+        let fault = Fault {
+            error: UmError::DivideByZero,
+            program_counter: 7,
+            instruction: Some(0x5000_0053),
+        };
+        let report = FaultReport::new(&fault, [0, 0, 0, 0, 0, 0, 0, 9], 2, 6);
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        assert_eq!(json.last(), Some(&b'\n'));
+
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["kind"], "divide_by_zero");
+        assert_eq!(value["message"], "division by zero");
+        assert_eq!(value["pc"], 7);
+        assert_eq!(value["instruction"]["word"], 0x5000_0053);
+        assert_eq!(value["instruction"]["operation"], "div");
+        assert_eq!(value["instruction"]["text"], "div r1, r2, r3");
+        assert_eq!(value["registers"][7], 9);
+        assert_eq!(value["segments"], 2);
+        assert_eq!(value["instructions"], 6);
+    }
+
+    #[test]
+    fn reports_faults_outside_the_program() {
+        let fault = Fault {
+            error: UmError::PcOutOfRange { length: 3 },
+            program_counter: 3,
+            instruction: None,
+        };
+        let report = FaultReport::new(&fault, [0; 8], 1, 3);
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["kind"], "pc_out_of_range");
+        assert!(value["instruction"].is_null());
+    }
+}
@@ -0,0 +1,185 @@
+use rand::Rng;
+use rumasm::rumasm::{
+    add, cmov, div, halt, input, load, loadp, loadv, map, mult, nand, output, store, unmap,
+};
+
+/// Registers r0 to r5 hold data; r6 and r7 only ever hold segment IDs.
+const DATA_REGISTERS: u32 = 6;
+const SEGMENT_REGISTERS: [u32; 2] = [6, 7];
+
+/// The longest segment a generated program maps.
+pub const MAX_SEGMENT_WORDS: u32 = 64;
+
+/// The most segments a generated program has mapped at once, including segment 0.
+pub const MAX_LIVE_SEGMENTS: usize = 1 + SEGMENT_REGISTERS.len();
+
+/// Generate a random program that is well formed, so it neither faults nor
+/// runs forever on a correct emulator.
+///
+/// The program first maps a segment into each of r6 and r7, then runs
+/// `blocks` random blocks of one to a few instructions, then halts:
+/// - loads and stores use offsets loaded just before, within the segment
+/// - a segment is only unmapped just before its register is mapped again
+/// - divisors are loaded with non-zero values just before
+/// - LoadProgram only jumps forwards within segment 0, possibly over unused
+///   random words, so every instruction is executed at most once
+///
+/// # Arguments
+/// - `rng`: the source of randomness
+/// - `blocks`: the number of random blocks
+pub fn program(rng: &mut impl Rng, blocks: usize) -> Vec<u32> {
+    let mut generator = Generator {
+        rng,
+        words: vec![],
+        lengths: [0; 2],
+    };
+    for segment in 0..SEGMENT_REGISTERS.len() {
+        generator.map(segment);
+    }
+    for _ in 0..blocks {
+        generator.block();
+    }
+    generator.words.push(halt());
+    generator.words
+}
+
+struct Generator<'a, R: Rng> {
+    rng: &'a mut R,
+    words: Vec<u32>,
+    /// The length of the segment currently mapped in each segment register.
+    lengths: [u32; 2],
+}
+
+impl<R: Rng> Generator<'_, R> {
+    fn data(&mut self) -> u32 {
+        self.rng.gen_range(0..DATA_REGISTERS)
+    }
+
+    fn segment(&mut self) -> usize {
+        self.rng.gen_range(0..SEGMENT_REGISTERS.len())
+    }
+
+    /// Map a new segment into a segment register.
+    fn map(&mut self, segment: usize) {
+        let length = self.rng.gen_range(1..=MAX_SEGMENT_WORDS);
+        let register = self.data();
+        self.words.extend([
+            loadv(register, length),
+            map(SEGMENT_REGISTERS[segment], register),
+        ]);
+        self.lengths[segment] = length;
+    }
+
+    /// Load a data register with an offset inside `segment`.
+    fn offset(&mut self, segment: usize) -> u32 {
+        let register = self.data();
+        let offset = self.rng.gen_range(0..self.lengths[segment]);
+        self.words.push(loadv(register, offset));
+        register
+    }
+
+    /// Add a random block of instructions.
+    fn block(&mut self) {
+        let (a, b, c) = (self.data(), self.data(), self.data());
+        let word = match self.rng.gen_range(0..12) {
+            0 => cmov(a, b, c),
+            1 => {
+                let segment = self.segment();
+                let offset = self.offset(segment);
+                load(a, SEGMENT_REGISTERS[segment], offset)
+            }
+            2 => {
+                let segment = self.segment();
+                let offset = self.offset(segment);
+                store(SEGMENT_REGISTERS[segment], offset, c)
+            }
+            3 => add(a, b, c),
+            4 => mult(a, b, c),
+            5 => {
+                let divisor = self.rng.gen_range(1..1 << 25);
+                self.words.push(loadv(c, divisor));
+                div(a, b, c)
+            }
+            6 => nand(a, b, c),
+            7 => {
+                let segment = self.segment();
+                self.words.push(unmap(SEGMENT_REGISTERS[segment]));
+                return self.map(segment);
+            }
+            8 => output(c),
+            9 => input(c),
+            10 => return self.jump(),
+            _ => loadv(a, self.rng.gen_range(0..1 << 25)),
+        };
+        self.words.push(word);
+    }
+
+    /// Jump forwards over a few random words that are never executed.
+    fn jump(&mut self) {
+        let zero = self.data();
+        let target = (zero + self.rng.gen_range(1..DATA_REGISTERS)) % DATA_REGISTERS;
+        let skipped = self.rng.gen_range(0..4);
+        let destination = self.words.len() as u32 + 3 + skipped;
+        self.words.extend([
+            loadv(zero, 0),
+            loadv(target, destination),
+            loadp(zero, target),
+        ]);
+        for _ in 0..skipped {
+            let word = self.rng.gen();
+            self.words.push(word);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        rumio::Streams,
+        rummem::{BTreeMemory, FreeListMemory, Limited, Limits, SegmentStore},
+        rumrun::{Machine, Outcome},
+    };
+
+    /// Run a generated program, checking that it halts within one step per
+    /// word and never holds more memory than the generator allows.
+    fn runs_cleanly<M: SegmentStore>(
+        memory: M,
+        program: &[u32],
+        input: &[u8],
+    ) -> Result<(), TestCaseError> {
+        let mut memory = Limited::new(memory, Limits::default());
+        memory.load_program(program.to_vec());
+        let mut machine = Machine::with_io(memory, Streams::new(input, Vec::new()));
+
+        let mut outcome = Ok(Outcome::Yielded);
+        for _ in 0..program.len() {
+            outcome = machine.run_for(1);
+            let usage = machine.memory().usage();
+            prop_assert!(usage.segments <= MAX_LIVE_SEGMENTS);
+            prop_assert!(usage.words <= (program.len() + 2 * MAX_SEGMENT_WORDS as usize) as u64);
+            if outcome != Ok(Outcome::Yielded) {
+                break;
+            }
+        }
+        prop_assert_eq!(outcome, Ok(Outcome::Halted));
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn generated_programs_run_cleanly(
+            seed in any::<u64>(),
+            blocks in 0..400_usize,
+            input in proptest::collection::vec(any::<u8>(), 0..8),
+        ) {
+            let program = program(&mut StdRng::seed_from_u64(seed), blocks);
+            // Checked memory also catches any use of an unmapped segment
+            runs_cleanly(FreeListMemory::checked(), &program, &input)?;
+            runs_cleanly(BTreeMemory::new(), &program, &input)?;
+        }
+    }
+}
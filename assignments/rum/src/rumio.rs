@@ -1,13 +1,170 @@
-use std::io::{stdin, stdout, Read, Write};
+use std::io::{self, stdin, stdout, ErrorKind, Read, Stdin, Stdout, Write};
 
-/// Send a single byte to the output device.
-pub fn output(value: u8) {
-    let mut stdout = stdout().lock();
-    let _ = stdout.write_all(&value.to_be_bytes());
-    let _ = stdout.flush();
+/// The I/O device attached to a Universal Machine.
+///
+/// The Output and Input instructions are forwarded to `output` and `input`.
+pub trait IoDevice {
+    /// Send a single byte to the output device.
+    fn output(&mut self, value: u8) -> io::Result<()>;
+
+    /// Get a single byte from the input device.
+    ///
+    /// # Returns
+    /// The byte, or `None` if the input is exhausted
+    fn input(&mut self) -> io::Result<Option<u8>>;
+
+    /// Deliver any buffered output. The machine calls this when it halts or faults.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Send a byte for the Output instruction executed after `step` others.
+    ///
+    /// The machine calls this rather than `output`, so devices that need to
+    /// know when output happens (such as `rumreplay::Recorder`) can override it.
+    fn output_at(&mut self, _step: u64, value: u8) -> io::Result<()> {
+        self.output(value)
+    }
+
+    /// Get a byte for the Input instruction executed after `step` others.
+    ///
+    /// The machine calls this rather than `input`; see `output_at`.
+    fn input_at(&mut self, _step: u64) -> io::Result<Option<u8>> {
+        self.input()
+    }
+}
+
+/// An `IoDevice` that reads from any `Read` and writes to any `Write`, e.g.
+/// the process's stdin/stdout, a socket, or in-memory buffers in tests.
+///
+/// Input is read one byte per Input instruction, so `input` should be
+/// buffered (`Stdin`, `StdinLock`, a `BufReader` or a byte slice) rather than
+/// e.g. a bare `File`.
+///
+/// Output is flushed at each newline, before each Input (so prompts are
+/// seen) and when the machine halts or faults. Wrap `output` in a
+/// `BufWriter` to benefit; `set_buffered(false)` flushes every byte instead.
+pub struct Streams<R: Read, W: Write> {
+    pub input: R,
+    pub output: W,
+    buffered: bool,
+}
+
+impl<R: Read, W: Write> Streams<R, W> {
+    pub fn new(input: R, output: W) -> Streams<R, W> {
+        Streams {
+            input,
+            output,
+            buffered: true,
+        }
+    }
+
+    /// Choose whether output is flushed only when needed (the default) or after every byte.
+    pub fn set_buffered(&mut self, buffered: bool) {
+        self.buffered = buffered;
+    }
+}
+
+/// The process's stdin and stdout, locked only for the duration of each read or write.
+pub fn stdio() -> Streams<Stdin, Stdout> {
+    Streams::new(stdin(), stdout())
+}
+
+impl<R: Read, W: Write> IoDevice for Streams<R, W> {
+    fn output(&mut self, value: u8) -> io::Result<()> {
+        self.output.write_all(&[value])?;
+        if !self.buffered || value == b'\n' {
+            self.output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn input(&mut self) -> io::Result<Option<u8>> {
+        // A closed output device does not stop the machine reading input
+        let _ = self.output.flush();
+        let mut buffer = [0_u8; 1];
+        match self.input.read_exact(&mut buffer) {
+            Ok(()) => Ok(Some(buffer[0])),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
 }
 
-/// Get a single byte from the input device.
-pub fn input() -> u8 {
-    stdin().bytes().next().unwrap().unwrap()
+/// Input that is not available until it has been polled `0` times, like a
+/// non-blocking socket, and then a single byte `x`. Output is discarded.
+#[cfg(test)]
+pub(crate) struct Slow(pub u32);
+
+#[cfg(test)]
+impl IoDevice for Slow {
+    fn output(&mut self, _: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn input(&mut self) -> io::Result<Option<u8>> {
+        if self.0 == 0 {
+            return Ok(Some(b'x'));
+        }
+        self.0 -= 1;
+        Err(ErrorKind::WouldBlock.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_policy() {
+        let mut device = Streams::new(&b"x"[..], io::BufWriter::new(Vec::new()));
+        device.output(b'a').unwrap();
+        assert_eq!(device.output.get_ref(), b"");
+        device.output(b'\n').unwrap();
+        assert_eq!(device.output.get_ref(), b"a\n");
+        device.output(b'>').unwrap();
+        device.input().unwrap();
+        assert_eq!(device.output.get_ref(), b"a\n>");
+        device.output(b'b').unwrap();
+        device.flush().unwrap();
+        assert_eq!(device.output.get_ref(), b"a\n>b");
+
+        device.set_buffered(false);
+        device.output(b'c').unwrap();
+        assert_eq!(device.output.get_ref(), b"a\n>bc");
+    }
+
+    #[test]
+    fn streams_round_trip() {
+        let mut device = Streams::new(&b"hi"[..], Vec::new());
+        assert_eq!(device.input().unwrap(), Some(b'h'));
+        assert_eq!(device.input().unwrap(), Some(b'i'));
+        assert_eq!(device.input().unwrap(), None);
+        device.output(b'o').unwrap();
+        device.output(b'k').unwrap();
+        assert_eq!(device.output, b"ok");
+    }
+
+    #[test]
+    fn input_survives_closed_output() {
+        struct Closed;
+
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Err(ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        let mut device = Streams::new(&b"x"[..], Closed);
+        assert!(device.output(b'a').is_err());
+        assert_eq!(device.input().unwrap(), Some(b'x'));
+    }
 }
@@ -1,37 +1,295 @@
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, stdin, Cursor, ErrorKind, Read, Write},
+};
 
-/// Load the input file (`.um` or `.umz`) into memory.
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+/// Size of the chunks a program is read in.
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// Magic bytes at the start of a gzip stream.
+const GZIP_MAGIC: [u8; 3] = [0x1F, 0x8B, 0x08];
+/// Magic bytes at the start of a container. As a UM word this has the
+/// invalid opcode 15, so no runnable raw program can start with it.
+const CONTAINER_MAGIC: [u8; 4] = [0xF5, b'U', b'M', b'X'];
+/// Version of the container format.
+const CONTAINER_VERSION: u8 = 1;
+
+/// A loaded program and where execution starts.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Image {
+    pub words: Vec<u32>,
+    /// The offset in segment 0 of the first instruction (0 for a raw program).
+    pub entry_point: u32,
+}
+
+/// A reason a program could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The program could not be read.
+    Io(io::Error),
+    /// The program's length is not a whole number of words.
+    Truncated {
+        /// The offset of the first byte of the incomplete word.
+        offset: u64,
+        /// The number of bytes in the incomplete word.
+        trailing: usize,
+    },
+    /// A container was written in a version of the format this loader does not know.
+    Version(u8),
+    /// A container holds a different number of words than its header says.
+    Length { expected: u32, actual: usize },
+    /// A container's program does not match the checksum in its header.
+    Checksum { expected: u32, actual: u32 },
+    /// A container's entry point is outside its program.
+    EntryPoint { entry_point: u32, length: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Truncated { offset, trailing } => write!(
+                f,
+                "incomplete word of {} byte(s) at offset {}; a UM program must be a series of 32-bit words",
+                trailing, offset
+            ),
+            LoadError::Version(version) => {
+                write!(f, "unsupported UM container version {}", version)
+            }
+            LoadError::Length { expected, actual } => write!(
+                f,
+                "container should hold {} words but holds {}",
+                expected, actual
+            ),
+            LoadError::Checksum { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected 0x{:08x} but the program has 0x{:08x}",
+                expected, actual
+            ),
+            LoadError::EntryPoint {
+                entry_point,
+                length,
+            } => write!(
+                f,
+                "entry point {} is outside the program of {} words",
+                entry_point, length
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+/// Load a program (`.um` or `.umz`) from any reader.
+///
+/// The program is read in chunks and converted to words as it arrives, so
+/// `input` does not need to be buffered.
 ///
 /// # Arguments
-/// - `f`: the `File` object
+/// - `input`: the program, as a series of big-endian 32-bit words
 ///
 /// # Returns
 /// A `Vec` of `u32` words
 ///
-/// # Panics
-/// - If the number of bytes in `f` is not divisible by 4
-pub fn load(f: File) -> Vec<u32> {
-    let mut reader = BufReader::new(f);
-    let mut buffer = Vec::new();
-
-    // Read file into vector
-    let _ = reader.read_to_end(&mut buffer);
-    assert_eq!(
-        buffer.len() % 4,
-        0,
-        "Input file must be a series of 32-bit words."
-    );
-
-    // Convert u8's to u32's (values remain unchanged)
-    let u32_buffer: Vec<u32> = buffer.iter().map(|byte| byte.to_owned() as u32).collect();
-    // Split into chunks of 4 bytes
-    let chunked_bytes = u32_buffer.chunks(4).collect::<Vec<_>>();
-    // Combine each chunk into a single word
-    chunked_bytes
-        .iter()
-        .map(|chunk| chunk[0] << 24 | chunk[1] << 16 | chunk[2] << 8 | chunk[3])
-        .collect()
+/// # Errors
+/// - `Truncated` if the number of bytes in `input` is not divisible by 4
+/// - `Io` if `input` could not be read
+pub fn load(mut input: impl Read) -> Result<Vec<u32>, LoadError> {
+    let mut words = Vec::new();
+    let mut buffer = vec![0_u8; CHUNK_SIZE];
+    // Bytes at the start of `buffer` left over from the previous chunk
+    let mut pending = 0;
+    // Bytes converted to words so far
+    let mut offset = 0_u64;
+
+    loop {
+        let read = match input.read(&mut buffer[pending..]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let available = pending + read;
+        let whole = available - available % 4;
+        words.extend(
+            buffer[..whole]
+                .chunks_exact(4)
+                .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]])),
+        );
+        buffer.copy_within(whole..available, 0);
+        pending = available - whole;
+        offset += whole as u64;
+    }
+
+    if pending != 0 {
+        return Err(LoadError::Truncated {
+            offset,
+            trailing: pending,
+        });
+    }
+    Ok(words)
+}
+
+/// Load a program in any supported format from any reader.
+///
+/// The format is detected from the first bytes of `input`, which may be a
+/// raw program (as accepted by `load`), a container written by
+/// `write_container`, or either of these compressed with gzip (e.g. `.um.gz`).
+///
+/// The gzip magic is also a valid first word (a LoadSegment), so input that
+/// starts like gzip but has no complete gzip header is loaded as it is.
+///
+/// # Errors
+/// - As for `load`
+/// - `Io` if a gzip stream is corrupt (e.g. its CRC does not match)
+/// - `Version`, `Length`, `Checksum` or `EntryPoint` if a container is invalid
+pub fn load_image(input: impl Read) -> Result<Image, LoadError> {
+    let (start, mut input) = sniff(input, GZIP_MAGIC.len())?;
+    if start != GZIP_MAGIC {
+        return read_image(input);
+    }
+
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    let mut decoded = vec![];
+    let mut decoder = GzDecoder::new(&bytes[..]);
+    match decoder.read_to_end(&mut decoded) {
+        Ok(_) => read_image(&decoded[..]),
+        Err(_) if decoder.header().is_none() => read_image(&bytes[..]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Load a program in any supported format from the file at `path`, or from stdin if `path` is `-`.
+///
+/// # Errors
+/// As for `load_image`, including failure to open the file
+pub fn load_path(path: &str) -> Result<Image, LoadError> {
+    if path == "-" {
+        load_image(stdin().lock())
+    } else {
+        load_image(File::open(path)?)
+    }
+}
+
+/// Write a program as a container that records its entry point and checksum.
+///
+/// The container is the magic bytes `F5 55 4D 58` (`\xF5UMX`), a version
+/// byte and three zero bytes, then the entry point, the number of words and
+/// the CRC-32 of the program, followed by the program itself. All values are
+/// big-endian 32-bit words.
+///
+/// # Arguments
+/// - `image`: the program and its entry point
+/// - `compress`: whether to compress the container with gzip
+/// - `out`: where to write the container
+pub fn write_container(image: &Image, compress: bool, out: impl Write) -> io::Result<()> {
+    if compress {
+        let mut encoder = GzEncoder::new(out, Compression::best());
+        write_raw_container(image, &mut encoder)?;
+        encoder.finish()?.flush()
+    } else {
+        write_raw_container(image, out)
+    }
+}
+
+/// Write an uncompressed container, as described for `write_container`.
+fn write_raw_container(image: &Image, out: impl Write) -> io::Result<()> {
+    let mut out = io::BufWriter::new(out);
+    out.write_all(&CONTAINER_MAGIC)?;
+    out.write_all(&[CONTAINER_VERSION, 0, 0, 0])?;
+    out.write_all(&image.entry_point.to_be_bytes())?;
+    out.write_all(&(image.words.len() as u32).to_be_bytes())?;
+    out.write_all(&checksum(&image.words).to_be_bytes())?;
+    for word in &image.words {
+        out.write_all(&word.to_be_bytes())?;
+    }
+    out.flush()
+}
+
+/// Load a raw program or a container.
+fn read_image(input: impl Read) -> Result<Image, LoadError> {
+    let (start, mut input) = sniff(input, CONTAINER_MAGIC.len())?;
+    if start != CONTAINER_MAGIC {
+        return Ok(Image {
+            words: load(input)?,
+            entry_point: 0,
+        });
+    }
+
+    // The magic, version word, entry point, length and checksum
+    let mut header = [0_u8; 20];
+    input.read_exact(&mut header)?;
+    if header[4] != CONTAINER_VERSION {
+        return Err(LoadError::Version(header[4]));
+    }
+    let field =
+        |i: usize| u32::from_be_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+    let (entry_point, length, expected) = (field(8), field(12), field(16));
+
+    let words = load(input)?;
+    if words.len() != length as usize {
+        return Err(LoadError::Length {
+            expected: length,
+            actual: words.len(),
+        });
+    }
+    let actual = checksum(&words);
+    if actual != expected {
+        return Err(LoadError::Checksum { expected, actual });
+    }
+    if entry_point as usize >= words.len() {
+        return Err(LoadError::EntryPoint {
+            entry_point,
+            length: words.len(),
+        });
+    }
+    Ok(Image { words, entry_point })
+}
+
+/// Get the CRC-32 of a program's big-endian bytes.
+pub fn checksum(words: &[u32]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for word in words {
+        hasher.update(&word.to_be_bytes());
+    }
+    hasher.finalize()
+}
+
+/// Read up to `length` bytes from the start of `input`.
+///
+/// # Returns
+/// The bytes read, and a reader that yields them followed by the rest of `input`
+fn sniff<R: Read>(mut input: R, length: usize) -> io::Result<(Vec<u8>, impl Read)> {
+    let mut start = vec![0_u8; length];
+    let mut read = 0;
+    while read < length {
+        match input.read(&mut start[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    start.truncate(read);
+    Ok((start.clone(), Cursor::new(start).chain(input)))
 }
 
 #[cfg(test)]
@@ -41,7 +299,7 @@ mod tests {
         io::Write,
     };
 
-    use super::load;
+    use super::*;
 
     #[test]
     fn load_word() {
@@ -52,7 +310,7 @@ mod tests {
         f.write_all(&buffer).unwrap();
 
         let f = File::open(path).unwrap();
-        let word = load(f)[0];
+        let word = load(f).unwrap()[0];
 
         remove_file(path).unwrap();
 
@@ -60,4 +318,140 @@ mod tests {
         // Sanity check               v
         assert_ne!(word, 0b00110011100100011111000000100101);
     }
+
+    /// A reader that returns at most three bytes at a time, so words straddle reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn load_from_reader() {
+        let bytes = [0x70, 0, 0, 0, 0xD2, 0, 0, 0x2A, 1, 2, 3, 4];
+        assert_eq!(
+            load(Trickle(&bytes)).unwrap(),
+            vec![0x7000_0000, 0xD200_002A, 0x0102_0304]
+        );
+        assert!(load(&b""[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_program() {
+        let bytes = [0x70, 0, 0, 0, 0xD2, 0, 0];
+        match load(Trickle(&bytes)) {
+            Err(LoadError::Truncated { offset, trailing }) => {
+                assert_eq!((offset, trailing), (4, 3));
+            }
+            other => panic!("expected a truncated program, got {:?}", other),
+        }
+        assert_eq!(
+            load(&bytes[..5]).unwrap_err().to_string(),
+            "incomplete word of 1 byte(s) at offset 4; a UM program must be a series of 32-bit words"
+        );
+        assert!(matches!(
+            load_path("/nonexistent/program.um"),
+            Err(LoadError::Io(_))
+        ));
+    }
+
+    // loadv r1, 'A'; output r1; halt
+    const PROGRAM: [u32; 3] = [0xD200_0041, 0xA000_0001, 0x7000_0000];
+
+    fn container(entry_point: u32, compress: bool) -> Vec<u8> {
+        let image = Image {
+            words: PROGRAM.to_vec(),
+            entry_point,
+        };
+        let mut bytes = vec![];
+        write_container(&image, compress, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn detects_formats() {
+        let raw: Vec<u8> = PROGRAM.iter().flat_map(|w| w.to_be_bytes()).collect();
+        let mut gzipped = GzEncoder::new(vec![], Compression::default());
+        gzipped.write_all(&raw).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+
+        let expected = |entry_point| Image {
+            words: PROGRAM.to_vec(),
+            entry_point,
+        };
+        assert_eq!(load_image(&raw[..]).unwrap(), expected(0));
+        assert_eq!(load_image(Trickle(&gzipped)).unwrap(), expected(0));
+        assert_eq!(
+            load_image(Trickle(&container(1, false))).unwrap(),
+            expected(1)
+        );
+        assert_eq!(load_image(&container(2, true)[..]).unwrap(), expected(2));
+
+        // A raw program whose first word is a LoadSegment that looks like gzip
+        let words: [u32; 2] = [0x1F8B_0801, 0x7000_0000];
+        let raw: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert_eq!(load_image(Trickle(&raw)).unwrap().words, words);
+        assert_eq!(
+            load_image(&b""[..]).unwrap(),
+            Image {
+                words: vec![],
+                entry_point: 0
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_gzip() {
+        let mut gzipped = GzEncoder::new(vec![], Compression::default());
+        gzipped.write_all(b"not a UM program").unwrap();
+        let gzipped = gzipped.finish().unwrap();
+        // Corrupt the compressed data, the CRC-32 and the length in turn
+        let end = gzipped.len();
+        for index in [12, end - 8, end - 1] {
+            let mut corrupt = gzipped.clone();
+            corrupt[index] ^= 0x55;
+            assert!(matches!(load_image(&corrupt[..]), Err(LoadError::Io(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_containers() {
+        let mut corrupt = container(0, false);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert!(matches!(
+            load_image(&corrupt[..]),
+            Err(LoadError::Checksum { .. })
+        ));
+
+        let mut short = container(0, false);
+        short.truncate(short.len() - 4);
+        assert!(matches!(
+            load_image(&short[..]),
+            Err(LoadError::Length {
+                expected: 3,
+                actual: 2
+            })
+        ));
+
+        let mut version = container(0, false);
+        version[4] = 9;
+        assert!(matches!(
+            load_image(&version[..]),
+            Err(LoadError::Version(9))
+        ));
+
+        assert!(matches!(
+            load_image(&container(3, false)[..]),
+            Err(LoadError::EntryPoint {
+                entry_point: 3,
+                length: 3
+            })
+        ));
+    }
 }
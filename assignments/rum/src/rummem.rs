@@ -1,147 +1,325 @@
-use rand::{self, Rng};
-use std::{collections::BTreeMap, process::exit};
+use crate::rumerr::UmError;
 
-pub type Memory = BTreeMap<u32, Vec<u32>>;
+mod btree;
+mod freelist;
+mod limits;
+mod watch;
 
-/// Load a program into memory segment 0.
-///
-/// # Arguments
-/// - `memory`: the segmented memory of the UM
-/// - `program`: a `Vec` of UM words
-pub fn load_program(memory: &mut Memory, program: Vec<u32>) {
-    memory.insert(0, program);
-}
+pub use btree::BTreeMemory;
+pub use freelist::FreeListMemory;
+pub use limits::{Limit, Limited, Limits, Usage};
+pub use watch::{Access, AccessKind, HookId, Watched};
 
-/// Get the length of the currently loaded program.
-///
-/// # Arguments
-/// - `memory`: the segmented memory of the UM
+/// The memory backend used unless another is chosen at run time.
+/// Build with `--features btree-memory` to make `BTreeMemory` the default.
 ///
-/// # Returns
-/// The length of the program
-pub fn get_program_length(memory: &Memory) -> usize {
-    memory[&0].len()
-}
+/// Only `FreeListMemory` hands out the same segment IDs every time a program
+/// runs, so tests that compare runs or replay one use it explicitly.
+#[cfg(not(feature = "btree-memory"))]
+pub type Memory = FreeListMemory;
+#[cfg(feature = "btree-memory")]
+pub type Memory = BTreeMemory;
 
-/// Map a memory segment.
-///
-/// # Arguments
-/// - `memory`: the segmented memory of the UM
-/// - `length`: the length (in words) of the new segment
+/// The segmented memory of the UM.
 ///
-/// # Returns
-/// The index of the new memory segment
-pub fn map(memory: &mut Memory, length: u32) -> u32 {
-    if memory.keys().len() == u32::MAX as usize {
-        eprintln!("Out of memory!");
-        exit(1);
+/// Backends only need to provide segment bookkeeping; loads and stores are
+/// implemented here in terms of `segment` and `segment_mut`.
+pub trait SegmentStore: Default {
+    /// The name this backend is chosen by.
+    const BACKEND: Backend;
+
+    /// Load a program into memory segment 0.
+    ///
+    /// # Arguments
+    /// - `program`: a `Vec` of UM words
+    fn load_program(&mut self, program: Vec<u32>);
+
+    /// Replace the program with a copy of another segment, as the
+    /// LoadProgram instruction does.
+    ///
+    /// Both backends let segment 0 share the source segment's words instead,
+    /// copying them only when either segment is next stored to (or moving
+    /// them if the source is unmapped first), so loading a segment that is
+    /// never modified afterwards does not copy it.
+    ///
+    /// # Arguments
+    /// - `index`: the segment to copy into segment 0
+    ///
+    /// # Errors
+    /// - `UnmappedSegment` if `index` refers to an unmapped segment
+    /// - `OutOfMemory` if the copy cannot be allocated
+    fn load_segment(&mut self, index: u32) -> Result<(), UmError> {
+        let source = self.segment(index)?;
+        let mut program = allocate(source.len() as u32)?;
+        program.copy_from_slice(source);
+        self.load_program(program);
+        Ok(())
     }
-    let segment = vec![0_u32; length as usize];
-    let index = choose_open_index(memory);
-    memory.insert(index, segment);
-    index
-}
 
-/// Unmap a memory segment. Does nothing if the specified segment is not mapped.
-///
-/// # Arguments
-/// - `memory`: the segmented memory of the UM
-/// - `index`: index of segment to remove
-pub fn unmap(memory: &mut Memory, index: u32) {
-    memory.remove(&index);
-}
+    /// Map a memory segment of zeroes.
+    ///
+    /// # Arguments
+    /// - `length`: the length (in words) of the new segment
+    ///
+    /// # Returns
+    /// The index of the new memory segment
+    ///
+    /// # Errors
+    /// - `OutOfMemory` if every index is in use or the segment cannot be allocated
+    fn map(&mut self, length: u32) -> Result<u32, UmError>;
 
-/// Load a value from memory.
-///
-/// # Arguments
-/// - `memory`: the segmented memory of the UM
-/// - `segment_index`: the index of the segment to be loaded from
-/// - `offset`: the position within the segment to be loaded from
-///
-/// # Returns
-/// The value at the specified memory location
-///
-/// # Panics
-/// - If `segment_index` refers to an unmapped segment
-/// - If `offset` is greater than the length of the segment
-pub fn load(memory: &Memory, segment_index: u32, offset: u32) -> u32 {
-    memory[&segment_index][offset as usize]
-}
+    /// Unmap a memory segment.
+    ///
+    /// # Arguments
+    /// - `index`: index of segment to remove
+    ///
+    /// # Errors
+    /// - `UnmapProgram` if `index` is 0
+    /// - `UnmappedSegment` if `index` refers to an unmapped segment
+    fn unmap(&mut self, index: u32) -> Result<(), UmError>;
 
-/// Store a value in memory.
-///
-/// # Arguments
-/// - `memory`: the segmented memory of the UM
-/// - `value`: the value to store
-/// - `segment_index`: the index of the segment to be loaded from
-/// - `offset`: the position within the segment to be loaded from
-///
-/// # Panics
-/// - If `segment_index` refers to an unmapped segment
-/// - If `offset` is greater than the length of the segment
-pub fn store(memory: &mut Memory, value: u32, segment_index: u32, offset: u32) {
-    memory.get_mut(&segment_index).unwrap()[offset as usize] = value;
+    /// Get a mapped segment.
+    ///
+    /// # Errors
+    /// - `UnmappedSegment` if `index` refers to an unmapped segment (backends
+    ///   may only detect this for segments that have never been mapped, see
+    ///   `FreeListMemory::checked`)
+    fn segment(&self, index: u32) -> Result<&[u32], UmError>;
+
+    /// Get a mapped segment for writing.
+    ///
+    /// # Errors
+    /// - `UnmappedSegment` if `index` refers to an unmapped segment
+    fn segment_mut(&mut self, index: u32) -> Result<&mut [u32], UmError>;
+
+    /// Get the number of mapped segments, including segment 0.
+    fn live_segments(&self) -> usize;
+
+    /// Get the IDs of every mapped segment, including segment 0, in increasing order.
+    fn mapped_ids(&self) -> Vec<u32>;
+
+    /// Get the unmapped IDs that will be reused by `map`, in the order they
+    /// will be handed out. Backends that do not reuse IDs in a fixed order
+    /// return nothing.
+    fn free_ids(&self) -> Vec<u32> {
+        vec![]
+    }
+
+    /// Replace the contents of memory, e.g. with a saved snapshot.
+    ///
+    /// # Arguments
+    /// - `segments`: every mapped segment (including segment 0) and its ID
+    /// - `free_ids`: IDs to reuse, as returned by `free_ids`
+    fn restore(&mut self, segments: Vec<(u32, Vec<u32>)>, free_ids: Vec<u32>);
+
+    /// Get the currently loaded program (segment 0).
+    fn program(&self) -> &[u32] {
+        self.segment(0).unwrap_or(&[])
+    }
+
+    /// Get the length of the currently loaded program.
+    fn get_program_length(&self) -> usize {
+        self.program().len()
+    }
+
+    /// Load a value from memory.
+    ///
+    /// # Arguments
+    /// - `segment_index`: the index of the segment to be loaded from
+    /// - `offset`: the position within the segment to be loaded from
+    ///
+    /// # Returns
+    /// The value at the specified memory location
+    ///
+    /// # Errors
+    /// - `UnmappedSegment` if `segment_index` refers to an unmapped segment
+    /// - `OutOfBounds` if `offset` is not less than the length of the segment
+    fn load(&self, segment_index: u32, offset: u32) -> Result<u32, UmError> {
+        let segment = self.segment(segment_index)?;
+        segment
+            .get(offset as usize)
+            .copied()
+            .ok_or(UmError::OutOfBounds {
+                segment: segment_index,
+                offset,
+                length: segment.len(),
+            })
+    }
+
+    /// Store a value in memory.
+    ///
+    /// # Arguments
+    /// - `value`: the value to store
+    /// - `segment_index`: the index of the segment to be stored to
+    /// - `offset`: the position within the segment to be stored to
+    ///
+    /// # Errors
+    /// - `UnmappedSegment` if `segment_index` refers to an unmapped segment
+    /// - `OutOfBounds` if `offset` is not less than the length of the segment
+    fn store(&mut self, value: u32, segment_index: u32, offset: u32) -> Result<(), UmError> {
+        let segment = self.segment_mut(segment_index)?;
+        let length = segment.len();
+        let word = segment
+            .get_mut(offset as usize)
+            .ok_or(UmError::OutOfBounds {
+                segment: segment_index,
+                offset,
+                length,
+            })?;
+        *word = value;
+        Ok(())
+    }
 }
 
-/// Choose an index for mapping a new segment.
-fn choose_open_index(memory: &Memory) -> u32 {
-    let mut index = 0;
-    let mut rng = rand::thread_rng();
+/// The backends that can be chosen with `rum --memory <name>`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Backend {
+    BTree,
+    FreeList,
+}
 
-    while memory.contains_key(&index) {
-        index = rng.gen();
+impl Backend {
+    /// Get the backend with the given name (`btree` or `freelist`).
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "btree" => Some(Backend::BTree),
+            "freelist" => Some(Backend::FreeList),
+            _ => None,
+        }
     }
 
-    index
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::BTree => "btree",
+            Backend::FreeList => "freelist",
+        }
+    }
 }
-// fn choose_open_index(memory: &Memory) -> u32 {
-//     let mut index = 0;
 
-//     while memory.contains_key(&index) {
-//         index += 1;
-//     }
+impl Default for Backend {
+    fn default() -> Backend {
+        if cfg!(feature = "btree-memory") {
+            Backend::BTree
+        } else {
+            Backend::FreeList
+        }
+    }
+}
 
-//     index
-// }
+/// Allocate a zeroed segment, failing cleanly if the host is out of memory.
+fn allocate(length: u32) -> Result<Vec<u32>, UmError> {
+    let mut segment = Vec::new();
+    segment
+        .try_reserve_exact(length as usize)
+        .map_err(|_| UmError::OutOfMemory { length })?;
+    segment.resize(length as usize, 0_u32);
+    Ok(segment)
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn map_segment() {
-        let mut memory = Memory::new();
-        let index = map(&mut memory, 10);
-        assert_eq!(memory[&index].len(), 10);
+    /// Behaviour shared by every backend.
+    fn conformance<M: SegmentStore>() {
+        let mut memory = M::default();
+        memory.load_program(vec![1, 2, 3]);
+        assert_eq!(memory.program(), &[1, 2, 3]);
+        assert_eq!(memory.live_segments(), 1);
+
+        let index = memory.map(10).unwrap();
+        assert_ne!(index, 0);
+        assert_eq!(memory.segment(index).unwrap(), &[0; 10]);
+        assert_eq!(memory.live_segments(), 2);
+
+        memory.store(59, index, 3).unwrap();
+        assert_eq!(memory.load(index, 3), Ok(59));
+        assert_eq!(
+            memory.load(index, 10),
+            Err(UmError::OutOfBounds {
+                segment: index,
+                offset: 10,
+                length: 10
+            })
+        );
+        assert_eq!(
+            memory.store(1, index, 10),
+            Err(UmError::OutOfBounds {
+                segment: index,
+                offset: 10,
+                length: 10
+            })
+        );
+
+        let other = memory.map(0).unwrap();
+        assert_ne!(other, index);
+        memory.unmap(index).unwrap();
+        assert_eq!(memory.live_segments(), 2);
+        assert_eq!(memory.unmap(index), Err(UmError::UnmappedSegment(index)));
+        assert_eq!(memory.unmap(0), Err(UmError::UnmapProgram));
+        assert_eq!(memory.live_segments(), 2);
+
+        // Each unmapped ID is handed out at most once
+        let first = memory.map(1).unwrap();
+        let second = memory.map(1).unwrap();
+        assert_ne!(first, second);
+        assert_ne!(first, other);
+        assert_ne!(second, other);
+
+        let mut ids = vec![0, other, first, second];
+        ids.sort_unstable();
+        assert_eq!(memory.mapped_ids(), ids);
+
+        // LoadProgram copies are independent of their source
+        memory.store(7, first, 0).unwrap();
+        memory.load_segment(first).unwrap();
+        assert_eq!(memory.program(), &[7]);
+        memory.store(8, 0, 0).unwrap();
+        assert_eq!(memory.load(first, 0), Ok(7));
+        memory.load_segment(first).unwrap();
+        memory.store(9, first, 0).unwrap();
+        assert_eq!(memory.program(), &[7]);
+        let gone = memory.map(1).unwrap();
+        memory.store(4, gone, 0).unwrap();
+        memory.load_segment(gone).unwrap();
+        memory.unmap(gone).unwrap();
+        assert_eq!(memory.program(), &[4]);
+        assert_eq!(
+            memory.load_segment(gone),
+            Err(UmError::UnmappedSegment(gone))
+        );
+        memory.load_program(vec![1, 2, 3]);
+
+        let mut copy = M::default();
+        copy.restore(
+            ids.iter()
+                .map(|&id| (id, memory.segment(id).unwrap().to_vec()))
+                .collect(),
+            memory.free_ids(),
+        );
+        assert_eq!(copy.mapped_ids(), ids);
+        assert_eq!(copy.program(), &[1, 2, 3]);
+        assert_eq!(copy.free_ids(), memory.free_ids());
     }
 
     #[test]
-    fn unmap_segment() {
-        let mut memory = Memory::new();
-        let index = map(&mut memory, 10);
-        assert_eq!(memory.contains_key(&index), true);
-        unmap(&mut memory, index);
-        assert_eq!(memory.contains_key(&index), false);
+    fn btree_conformance() {
+        conformance::<BTreeMemory>();
     }
 
     #[test]
-    fn default_value() {
-        let mut memory = Memory::new();
-        let index = map(&mut memory, 10);
-
-        for i in 0..10 {
-            assert_eq!(memory[&index][i], 0);
-        }
+    fn freelist_conformance() {
+        conformance::<FreeListMemory>();
     }
 
     #[test]
-    fn load_store() {
-        let mut memory = Memory::new();
-        let index = map(&mut memory, 10);
-        let value = 59;
-        let offset = 3;
-        store(&mut memory, value, index, offset);
-        let loaded_value = load(&memory, index, offset);
-        assert_eq!(value, loaded_value);
+    fn backend_names() {
+        assert_eq!(Backend::from_name("btree"), Some(Backend::BTree));
+        assert_eq!(Backend::from_name("freelist"), Some(Backend::FreeList));
+        assert_eq!(Backend::from_name("vec"), None);
+        assert_eq!(
+            Backend::from_name(Backend::BTree.name()),
+            Some(Backend::BTree)
+        );
     }
 }
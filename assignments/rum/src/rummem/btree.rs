@@ -0,0 +1,186 @@
+use rand::{self, Rng};
+use std::collections::BTreeMap;
+
+use super::{allocate, Backend, SegmentStore};
+use crate::rumerr::UmError;
+
+/// Segments kept in a `BTreeMap`, each mapped at a randomly chosen free index.
+pub struct BTreeMemory {
+    segments: BTreeMap<u32, Vec<u32>>,
+    /// The segment whose words segment 0 holds: 0, or the segment last
+    /// loaded by `load_segment` if neither has been stored to since.
+    program_source: u32,
+}
+
+impl Default for BTreeMemory {
+    fn default() -> BTreeMemory {
+        // Segment 0 always exists, so it is never chosen for a new segment
+        BTreeMemory {
+            segments: BTreeMap::from([(0, vec![])]),
+            program_source: 0,
+        }
+    }
+}
+
+impl BTreeMemory {
+    pub fn new() -> BTreeMemory {
+        BTreeMemory::default()
+    }
+
+    /// Choose an index for mapping a new segment.
+    fn choose_open_index(&self) -> u32 {
+        let mut index = 0;
+        let mut rng = rand::thread_rng();
+
+        while self.segments.contains_key(&index) {
+            index = rng.gen();
+        }
+
+        index
+    }
+
+    /// Give segment 0 its own copy of the program if it shares another segment's.
+    fn unshare(&mut self) {
+        if self.program_source != 0 {
+            let program = self.segments[&self.program_source].clone();
+            self.segments.insert(0, program);
+            self.program_source = 0;
+        }
+    }
+}
+
+impl SegmentStore for BTreeMemory {
+    const BACKEND: Backend = Backend::BTree;
+
+    fn load_program(&mut self, program: Vec<u32>) {
+        self.segments.insert(0, program);
+        self.program_source = 0;
+    }
+
+    fn load_segment(&mut self, index: u32) -> Result<(), UmError> {
+        if !self.segments.contains_key(&index) {
+            return Err(UmError::UnmappedSegment(index));
+        }
+        if index != 0 {
+            self.segments.insert(0, vec![]);
+            self.program_source = index;
+        }
+        Ok(())
+    }
+
+    fn map(&mut self, length: u32) -> Result<u32, UmError> {
+        if self.segments.len() == u32::MAX as usize {
+            return Err(UmError::OutOfMemory { length });
+        }
+        let segment = allocate(length)?;
+        let index = self.choose_open_index();
+        self.segments.insert(index, segment);
+        Ok(index)
+    }
+
+    fn unmap(&mut self, index: u32) -> Result<(), UmError> {
+        if index == 0 {
+            return Err(UmError::UnmapProgram);
+        }
+        match self.segments.remove(&index) {
+            Some(segment) => {
+                if index == self.program_source {
+                    // Segment 0 takes over the words instead of copying them
+                    self.segments.insert(0, segment);
+                    self.program_source = 0;
+                }
+                Ok(())
+            }
+            None => Err(UmError::UnmappedSegment(index)),
+        }
+    }
+
+    fn segment(&self, index: u32) -> Result<&[u32], UmError> {
+        let source = match index {
+            0 => self.program_source,
+            index => index,
+        };
+        self.segments
+            .get(&source)
+            .map(Vec::as_slice)
+            .ok_or(UmError::UnmappedSegment(index))
+    }
+
+    fn segment_mut(&mut self, index: u32) -> Result<&mut [u32], UmError> {
+        if self.program_source != 0 && (index == 0 || index == self.program_source) {
+            self.unshare();
+        }
+        self.segments
+            .get_mut(&index)
+            .map(Vec::as_mut_slice)
+            .ok_or(UmError::UnmappedSegment(index))
+    }
+
+    fn live_segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    fn mapped_ids(&self) -> Vec<u32> {
+        self.segments.keys().copied().collect()
+    }
+
+    fn restore(&mut self, segments: Vec<(u32, Vec<u32>)>, _free_ids: Vec<u32>) {
+        self.segments = segments.into_iter().collect();
+        self.program_source = 0;
+        self.segments.entry(0).or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_segment() {
+        let mut memory = BTreeMemory::new();
+        let index = memory.map(10).unwrap();
+        assert_eq!(memory.segments[&index].len(), 10);
+    }
+
+    #[test]
+    fn unmap_segment() {
+        let mut memory = BTreeMemory::new();
+        let index = memory.map(10).unwrap();
+        assert!(memory.segments.contains_key(&index));
+        memory.unmap(index).unwrap();
+        assert!(!memory.segments.contains_key(&index));
+    }
+
+    #[test]
+    fn default_value() {
+        let mut memory = BTreeMemory::new();
+        let index = memory.map(10).unwrap();
+
+        for word in &memory.segments[&index] {
+            assert_eq!(*word, 0);
+        }
+    }
+
+    #[test]
+    fn load_store() {
+        let mut memory = BTreeMemory::new();
+        let index = memory.map(10).unwrap();
+        let value = 59;
+        let offset = 3;
+        memory.store(value, index, offset).unwrap();
+        let loaded_value = memory.load(index, offset).unwrap();
+        assert_eq!(value, loaded_value);
+    }
+
+    #[test]
+    fn memory_errors() {
+        let mut memory = BTreeMemory::new();
+        let index = memory.map(2).unwrap();
+        memory.unmap(index).unwrap();
+        assert_eq!(memory.unmap(index), Err(UmError::UnmappedSegment(index)));
+        assert_eq!(
+            memory.store(1, index, 0),
+            Err(UmError::UnmappedSegment(index))
+        );
+    }
+}
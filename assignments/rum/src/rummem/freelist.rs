@@ -0,0 +1,292 @@
+use super::{allocate, Backend, SegmentStore};
+use crate::rumerr::UmError;
+
+type Segment = Vec<u32>;
+
+/// Segments kept in a `Vec`, indexed directly by segment ID. Unmapped IDs go
+/// on a free list and are handed out again by later maps.
+///
+/// Unmapping a segment releases its contents. By default a later access to
+/// the released segment sees an empty segment (and so faults with
+/// `OutOfBounds`); in checked mode it faults with `UnmappedSegment` instead,
+/// at the cost of an extra check on every load and store.
+pub struct FreeListMemory {
+    memory: Vec<Segment>,
+    /// Whether each segment in `memory` is currently mapped.
+    mapped: Vec<bool>,
+    free_segments: Vec<usize>,
+    checked: bool,
+    /// The segment whose words segment 0 holds: 0, or the segment last
+    /// loaded by `load_segment` if neither has been stored to since.
+    program_source: usize,
+}
+
+impl Default for FreeListMemory {
+    fn default() -> FreeListMemory {
+        FreeListMemory {
+            memory: vec![vec![]; 1],
+            mapped: vec![true],
+            free_segments: vec![],
+            checked: false,
+            program_source: 0,
+        }
+    }
+}
+
+impl FreeListMemory {
+    pub fn new() -> FreeListMemory {
+        FreeListMemory::default()
+    }
+
+    /// Create memory that reports every use of an unmapped segment as `UnmappedSegment`.
+    pub fn checked() -> FreeListMemory {
+        FreeListMemory {
+            checked: true,
+            ..FreeListMemory::default()
+        }
+    }
+
+    fn is_mapped(&self, index: u32) -> bool {
+        self.mapped.get(index as usize).copied().unwrap_or(false)
+    }
+
+    /// Check that `index` may be accessed.
+    ///
+    /// # Errors
+    /// - `UnmappedSegment` if `index` was never mapped, or (in checked mode) has been unmapped
+    fn check(&self, index: u32) -> Result<usize, UmError> {
+        let in_range = (index as usize) < self.memory.len();
+        if !in_range || (self.checked && !self.mapped[index as usize]) {
+            return Err(UmError::UnmappedSegment(index));
+        }
+        Ok(index as usize)
+    }
+
+    /// Give segment 0 its own copy of the program if it shares another segment's.
+    fn unshare(&mut self) {
+        if self.program_source != 0 {
+            self.memory[0] = self.memory[self.program_source].clone();
+            self.program_source = 0;
+        }
+    }
+}
+
+impl SegmentStore for FreeListMemory {
+    const BACKEND: Backend = Backend::FreeList;
+
+    fn load_program(&mut self, program: Vec<u32>) {
+        self.memory[0] = program;
+        self.program_source = 0;
+    }
+
+    fn load_segment(&mut self, index: u32) -> Result<(), UmError> {
+        if !self.is_mapped(index) {
+            return Err(UmError::UnmappedSegment(index));
+        }
+        if index != 0 {
+            self.memory[0] = Vec::new();
+            self.program_source = index as usize;
+        }
+        Ok(())
+    }
+
+    fn map(&mut self, length: u32) -> Result<u32, UmError> {
+        let segment = allocate(length)?;
+        match self.free_segments.pop() {
+            Some(index) => {
+                self.memory[index] = segment;
+                self.mapped[index] = true;
+                Ok(index as u32)
+            }
+            None if self.memory.len() > u32::MAX as usize => Err(UmError::OutOfMemory { length }),
+            None => {
+                self.memory.push(segment);
+                self.mapped.push(true);
+                Ok((self.memory.len() - 1) as u32)
+            }
+        }
+    }
+
+    fn unmap(&mut self, index: u32) -> Result<(), UmError> {
+        if index == 0 {
+            return Err(UmError::UnmapProgram);
+        }
+        if !self.is_mapped(index) {
+            return Err(UmError::UnmappedSegment(index));
+        }
+        if index as usize == self.program_source {
+            // Segment 0 takes over the words instead of copying them
+            self.memory[0] = std::mem::take(&mut self.memory[index as usize]);
+            self.program_source = 0;
+        }
+        self.memory[index as usize] = Vec::new();
+        self.mapped[index as usize] = false;
+        self.free_segments.push(index as usize);
+        Ok(())
+    }
+
+    fn segment(&self, index: u32) -> Result<&[u32], UmError> {
+        let index = match self.check(index)? {
+            0 => self.program_source,
+            index => index,
+        };
+        Ok(&self.memory[index])
+    }
+
+    fn segment_mut(&mut self, index: u32) -> Result<&mut [u32], UmError> {
+        let index = self.check(index)?;
+        if self.program_source != 0 && (index == 0 || index == self.program_source) {
+            self.unshare();
+        }
+        Ok(&mut self.memory[index])
+    }
+
+    fn live_segments(&self) -> usize {
+        self.memory.len() - self.free_segments.len()
+    }
+
+    fn mapped_ids(&self) -> Vec<u32> {
+        (0..self.memory.len() as u32)
+            .filter(|&index| self.mapped[index as usize])
+            .collect()
+    }
+
+    fn free_ids(&self) -> Vec<u32> {
+        // `map` pops from the end of the free list
+        self.free_segments
+            .iter()
+            .rev()
+            .map(|&index| index as u32)
+            .collect()
+    }
+
+    fn restore(&mut self, segments: Vec<(u32, Vec<u32>)>, free_ids: Vec<u32>) {
+        let length = segments
+            .iter()
+            .map(|&(index, _)| index)
+            .chain(free_ids.iter().copied())
+            .max()
+            .unwrap_or(0) as usize
+            + 1;
+        self.memory = vec![vec![]; length];
+        self.mapped = vec![false; length];
+        self.program_source = 0;
+        self.mapped[0] = true;
+        for (index, segment) in segments {
+            self.memory[index as usize] = segment;
+            self.mapped[index as usize] = true;
+        }
+
+        // Reuse the saved IDs in their saved order, then any others that are free
+        let mut listed = vec![false; length];
+        for &index in &free_ids {
+            listed[index as usize] = true;
+        }
+        let mut free_segments: Vec<usize> = free_ids.iter().rev().map(|&i| i as usize).collect();
+        let unlisted = (1..length).filter(|&index| !self.mapped[index] && !listed[index]);
+        free_segments.splice(0..0, unlisted);
+        self.free_segments = free_segments;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_segment() {
+        let mut memory = FreeListMemory::new();
+        let index = memory.map(10).unwrap();
+        assert_eq!(memory.memory[index as usize].len(), 10);
+    }
+
+    #[test]
+    fn unmap_segment() {
+        let mut memory = FreeListMemory::new();
+        let index = memory.map(10).unwrap();
+        assert_eq!(memory.segment(index), Ok(vec![0; 10].as_ref()));
+        assert_eq!(memory.free_segments.len(), 0);
+        memory.unmap(index).unwrap();
+        assert_eq!(memory.free_segments.len(), 1);
+        assert_eq!(memory.free_segments[0], 1);
+        memory.map(50).unwrap();
+        assert_eq!(memory.segment(index), Ok(vec![0; 50].as_ref()));
+    }
+
+    #[test]
+    fn default_value() {
+        let mut memory = FreeListMemory::new();
+        let index = memory.map(10).unwrap();
+
+        for word in &memory.memory[index as usize] {
+            assert_eq!(*word, 0);
+        }
+    }
+
+    #[test]
+    fn double_unmap_is_rejected() {
+        let mut memory = FreeListMemory::new();
+        let index = memory.map(1).unwrap();
+        memory.unmap(index).unwrap();
+        assert_eq!(memory.unmap(index), Err(UmError::UnmappedSegment(index)));
+        assert_eq!(memory.free_segments, vec![index as usize]);
+        assert_eq!(memory.map(1), Ok(index));
+        assert_ne!(memory.map(1), Ok(index));
+        assert_eq!(memory.unmap(0), Err(UmError::UnmapProgram));
+        assert_eq!(memory.unmap(99), Err(UmError::UnmappedSegment(99)));
+    }
+
+    #[test]
+    fn use_after_unmap() {
+        let mut unchecked = FreeListMemory::new();
+        let index = unchecked.map(4).unwrap();
+        unchecked.store(7, index, 2).unwrap();
+        unchecked.unmap(index).unwrap();
+        assert_eq!(
+            unchecked.load(index, 2),
+            Err(UmError::OutOfBounds {
+                segment: index,
+                offset: 2,
+                length: 0
+            })
+        );
+
+        let mut checked = FreeListMemory::checked();
+        let index = checked.map(4).unwrap();
+        checked.unmap(index).unwrap();
+        assert_eq!(checked.load(index, 2), Err(UmError::UnmappedSegment(index)));
+        assert_eq!(
+            checked.store(1, index, 0),
+            Err(UmError::UnmappedSegment(index))
+        );
+        let index = checked.map(4).unwrap();
+        assert_eq!(checked.load(index, 2), Ok(0));
+    }
+
+    #[test]
+    fn restore_keeps_reuse_order() {
+        let mut memory = FreeListMemory::new();
+        let ids: Vec<u32> = (0..4).map(|_| memory.map(1).unwrap()).collect();
+        memory.unmap(ids[1]).unwrap();
+        memory.unmap(ids[3]).unwrap();
+        assert_eq!(memory.free_ids(), vec![ids[3], ids[1]]);
+
+        let mut copy = FreeListMemory::new();
+        copy.restore(vec![(0, vec![]), (ids[2], vec![5])], memory.free_ids());
+        // IDs not listed as free (here `ids[0]`) are reused after the listed ones
+        assert_eq!(copy.free_ids(), vec![ids[3], ids[1], ids[0]]);
+        assert_eq!(copy.map(1), memory.map(1));
+        assert_eq!(copy.load(ids[2], 0), Ok(5));
+    }
+
+    #[test]
+    fn load_store() {
+        let mut memory = FreeListMemory::new();
+        let index = memory.map(10).unwrap();
+        let value = 59;
+        let offset = 3;
+        memory.store(value, index, offset).unwrap();
+        let loaded_value = memory.load(index, offset).unwrap();
+        assert_eq!(value, loaded_value);
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+
+use super::{Backend, SegmentStore};
+use crate::rumerr::UmError;
+
+/// Bounds on the memory a UM program may map. `None` leaves a resource unlimited.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Limits {
+    /// The most words that may be mapped at once, counting segment 0.
+    pub total_words: Option<u64>,
+    /// The longest segment that may be mapped, in words.
+    pub segment_words: Option<u32>,
+    /// The most segments that may be mapped at once, counting segment 0.
+    pub live_segments: Option<usize>,
+}
+
+/// A limit that a program tried to exceed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Limit {
+    TotalWords(u64),
+    SegmentWords(u32),
+    LiveSegments(usize),
+}
+
+/// How much memory was mapped at some point in a run.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Usage {
+    /// Words mapped across all segments, including segment 0.
+    pub words: u64,
+    /// Mapped segments, including segment 0.
+    pub segments: usize,
+}
+
+/// Memory that enforces `Limits` on top of another backend.
+///
+/// Mapping a segment, or loading a copy of one as the program, fails with
+/// `LimitExceeded` if it would take the program past any limit, so a
+/// hostile program cannot exhaust the host's memory.
+#[derive(Default)]
+pub struct Limited<M: SegmentStore> {
+    memory: M,
+    limits: Limits,
+    /// Words mapped in `memory`.
+    words: u64,
+}
+
+impl<M: SegmentStore> Limited<M> {
+    /// # Arguments
+    /// - `memory`: the backend to keep segments in
+    /// - `limits`: the limits to enforce on later maps
+    pub fn new(memory: M, limits: Limits) -> Limited<M> {
+        let mut limited = Limited {
+            memory,
+            limits,
+            words: 0,
+        };
+        limited.words = limited.count_words();
+        limited
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Get the memory currently mapped.
+    pub fn usage(&self) -> Usage {
+        Usage {
+            words: self.words,
+            segments: self.memory.live_segments(),
+        }
+    }
+
+    fn count_words(&self) -> u64 {
+        self.memory
+            .mapped_ids()
+            .into_iter()
+            .map(|id| {
+                self.memory
+                    .segment(id)
+                    .map_or(0, |segment| segment.len() as u64)
+            })
+            .sum()
+    }
+
+    /// Check that a segment of `length` words may be allocated.
+    ///
+    /// # Arguments
+    /// - `length`: the length of the new segment
+    /// - `replaces`: the length of a segment it replaces, or `None` if it is an extra segment
+    ///
+    /// # Errors
+    /// - `LimitExceeded` naming the first limit that would be exceeded
+    fn check(&self, length: u32, replaces: Option<u64>) -> Result<(), UmError> {
+        let exceeded = |limit| {
+            Err(UmError::LimitExceeded {
+                limit,
+                length,
+                usage: self.usage(),
+            })
+        };
+        if let Some(max) = self.limits.segment_words {
+            if length > max {
+                return exceeded(Limit::SegmentWords(max));
+            }
+        }
+        if let Some(max) = self.limits.live_segments {
+            if replaces.is_none() && self.memory.live_segments() >= max {
+                return exceeded(Limit::LiveSegments(max));
+            }
+        }
+        if let Some(max) = self.limits.total_words {
+            if self.words - replaces.unwrap_or(0) + length as u64 > max {
+                return exceeded(Limit::TotalWords(max));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M: SegmentStore> SegmentStore for Limited<M> {
+    const BACKEND: Backend = M::BACKEND;
+
+    fn load_program(&mut self, program: Vec<u32>) {
+        self.words = self.words - self.memory.program().len() as u64 + program.len() as u64;
+        self.memory.load_program(program);
+    }
+
+    fn load_segment(&mut self, index: u32) -> Result<(), UmError> {
+        let length = self.memory.segment(index)?.len();
+        let replaced = self.memory.program().len() as u64;
+        self.check(length as u32, Some(replaced))?;
+        self.memory.load_segment(index)?;
+        self.words = self.words - replaced + length as u64;
+        Ok(())
+    }
+
+    fn map(&mut self, length: u32) -> Result<u32, UmError> {
+        self.check(length, None)?;
+        let index = self.memory.map(length)?;
+        self.words += length as u64;
+        Ok(index)
+    }
+
+    fn unmap(&mut self, index: u32) -> Result<(), UmError> {
+        let length = self
+            .memory
+            .segment(index)
+            .map_or(0, |segment| segment.len());
+        self.memory.unmap(index)?;
+        self.words -= length as u64;
+        Ok(())
+    }
+
+    fn segment(&self, index: u32) -> Result<&[u32], UmError> {
+        self.memory.segment(index)
+    }
+
+    fn segment_mut(&mut self, index: u32) -> Result<&mut [u32], UmError> {
+        self.memory.segment_mut(index)
+    }
+
+    fn live_segments(&self) -> usize {
+        self.memory.live_segments()
+    }
+
+    fn mapped_ids(&self) -> Vec<u32> {
+        self.memory.mapped_ids()
+    }
+
+    fn free_ids(&self) -> Vec<u32> {
+        self.memory.free_ids()
+    }
+
+    fn restore(&mut self, segments: Vec<(u32, Vec<u32>)>, free_ids: Vec<u32>) {
+        // A snapshot is trusted to have been within the limits when it was taken
+        self.memory.restore(segments, free_ids);
+        self.words = self.count_words();
+    }
+
+    fn load(&self, segment_index: u32, offset: u32) -> Result<u32, UmError> {
+        self.memory.load(segment_index, offset)
+    }
+
+    fn store(&mut self, value: u32, segment_index: u32, offset: u32) -> Result<(), UmError> {
+        self.memory.store(value, segment_index, offset)
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::TotalWords(max) => write!(f, "limit of {} words in total", max),
+            Limit::SegmentWords(max) => write!(f, "limit of {} words per segment", max),
+            Limit::LiveSegments(max) => write!(f, "limit of {} live segments", max),
+        }
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} words mapped in {} segment(s)",
+            self.words, self.segments
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rummem::{BTreeMemory, FreeListMemory};
+
+    fn limited<M: SegmentStore>(limits: Limits) -> Limited<M> {
+        let mut memory = M::default();
+        memory.load_program(vec![0; 4]);
+        Limited::new(memory, limits)
+    }
+
+    fn enforces_limits<M: SegmentStore>() {
+        let mut memory = limited::<M>(Limits {
+            total_words: Some(20),
+            segment_words: Some(10),
+            live_segments: Some(3),
+        });
+        assert_eq!(
+            memory.usage(),
+            Usage {
+                words: 4,
+                segments: 1
+            }
+        );
+
+        assert_eq!(
+            memory.map(11),
+            Err(UmError::LimitExceeded {
+                limit: Limit::SegmentWords(10),
+                length: 11,
+                usage: memory.usage()
+            })
+        );
+        let first = memory.map(10).unwrap();
+        assert!(matches!(
+            memory.map(7),
+            Err(UmError::LimitExceeded {
+                limit: Limit::TotalWords(20),
+                ..
+            })
+        ));
+        let second = memory.map(6).unwrap();
+        assert!(matches!(
+            memory.map(0),
+            Err(UmError::LimitExceeded {
+                limit: Limit::LiveSegments(3),
+                ..
+            })
+        ));
+
+        // Unmapping and replacing the program both release words
+        memory.unmap(second).unwrap();
+        memory.load_segment(first).unwrap();
+        assert_eq!(
+            memory.usage(),
+            Usage {
+                words: 20,
+                segments: 2
+            }
+        );
+        memory.load_program(vec![1]);
+        assert!(memory.map(9).is_ok());
+        assert_eq!(memory.usage().words, 20);
+    }
+
+    #[test]
+    fn btree_limits() {
+        enforces_limits::<BTreeMemory>();
+    }
+
+    #[test]
+    fn freelist_limits() {
+        enforces_limits::<FreeListMemory>();
+    }
+
+    #[test]
+    fn load_segment_is_limited() {
+        let mut memory = limited::<FreeListMemory>(Limits {
+            total_words: Some(12),
+            ..Limits::default()
+        });
+        let index = memory.map(8).unwrap();
+        assert!(matches!(
+            memory.load_segment(index),
+            Err(UmError::LimitExceeded {
+                limit: Limit::TotalWords(12),
+                length: 8,
+                ..
+            })
+        ));
+        assert_eq!(memory.program(), &[0; 4]);
+
+        memory.restore(vec![(0, vec![0; 2]), (5, vec![0; 3])], vec![]);
+        assert_eq!(
+            memory.usage(),
+            Usage {
+                words: 5,
+                segments: 2
+            }
+        );
+    }
+
+    #[test]
+    fn reports_usage() {
+        let mut memory = limited::<FreeListMemory>(Limits {
+            live_segments: Some(1),
+            ..Limits::default()
+        });
+        assert_eq!(
+            memory.map(100).unwrap_err().to_string(),
+            "mapping a segment of 100 words would exceed the limit of 1 live segments \
+             (4 words mapped in 1 segment(s))"
+        );
+    }
+}
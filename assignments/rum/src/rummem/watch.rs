@@ -0,0 +1,307 @@
+use std::{cell::RefCell, ops::Range};
+
+use super::{Backend, SegmentStore};
+use crate::rumerr::UmError;
+
+/// A load or store that a hook was called for.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Access {
+    pub segment: u32,
+    pub offset: u32,
+    pub kind: AccessKind,
+}
+
+/// Whether an access read or wrote a word, and the word's value.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AccessKind {
+    Read(u32),
+    Write { old: u32, new: u32 },
+}
+
+/// Identifies a hook so that it can be removed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct HookId(usize);
+
+/// A callback for the accesses to a range of offsets in one segment.
+struct Hook {
+    id: HookId,
+    segment: u32,
+    offsets: Range<u32>,
+    /// Whether the hook is called for loads, rather than stores.
+    reads: bool,
+    callback: Box<dyn FnMut(Access)>,
+}
+
+impl Hook {
+    fn watches(&self, reads: bool, segment: u32, offset: u32) -> bool {
+        self.reads == reads && self.segment == segment && self.offsets.contains(&offset)
+    }
+}
+
+/// Memory that calls hooks registered by the caller when a watched word is
+/// loaded or stored, on top of another backend, e.g. for watchpoints, taint
+/// tracking or data coverage.
+///
+/// Hooks see the `load` and `store` calls made by the Load and Store
+/// instructions, and only those that succeed. Instruction fetches, and the
+/// copy made by LoadProgram, are not accesses. A hook watches a segment ID,
+/// so it also sees accesses to a later segment that reuses an unmapped ID.
+#[derive(Default)]
+pub struct Watched<M: SegmentStore> {
+    memory: M,
+    /// Behind a `RefCell` because `load` only borrows memory immutably.
+    hooks: RefCell<Vec<Hook>>,
+    next_id: usize,
+}
+
+impl<M: SegmentStore> Watched<M> {
+    /// # Arguments
+    /// - `memory`: the backend to keep segments in
+    pub fn new(memory: M) -> Watched<M> {
+        Watched {
+            memory,
+            hooks: RefCell::new(vec![]),
+            next_id: 0,
+        }
+    }
+
+    /// Call `callback` after each load from `offsets` in `segment`.
+    ///
+    /// # Returns
+    /// An ID that removes the hook when passed to `unhook`
+    pub fn on_read(
+        &mut self,
+        segment: u32,
+        offsets: Range<u32>,
+        callback: impl FnMut(Access) + 'static,
+    ) -> HookId {
+        self.hook(true, segment, offsets, Box::new(callback))
+    }
+
+    /// Call `callback` after each store to `offsets` in `segment`.
+    ///
+    /// # Returns
+    /// An ID that removes the hook when passed to `unhook`
+    pub fn on_write(
+        &mut self,
+        segment: u32,
+        offsets: Range<u32>,
+        callback: impl FnMut(Access) + 'static,
+    ) -> HookId {
+        self.hook(false, segment, offsets, Box::new(callback))
+    }
+
+    fn hook(
+        &mut self,
+        reads: bool,
+        segment: u32,
+        offsets: Range<u32>,
+        callback: Box<dyn FnMut(Access)>,
+    ) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        self.hooks.get_mut().push(Hook {
+            id,
+            segment,
+            offsets,
+            reads,
+            callback,
+        });
+        id
+    }
+
+    /// Remove a hook.
+    ///
+    /// # Returns
+    /// Whether the hook was registered
+    pub fn unhook(&mut self, id: HookId) -> bool {
+        let hooks = self.hooks.get_mut();
+        let count = hooks.len();
+        hooks.retain(|hook| hook.id != id);
+        hooks.len() < count
+    }
+
+    /// Call each hook watching `access`, in the order they were registered.
+    fn notify(hooks: &mut [Hook], access: Access) {
+        let reads = matches!(access.kind, AccessKind::Read(_));
+        for hook in hooks {
+            if hook.watches(reads, access.segment, access.offset) {
+                (hook.callback)(access);
+            }
+        }
+    }
+}
+
+impl<M: SegmentStore> SegmentStore for Watched<M> {
+    const BACKEND: Backend = M::BACKEND;
+
+    fn load_program(&mut self, program: Vec<u32>) {
+        self.memory.load_program(program);
+    }
+
+    fn load_segment(&mut self, index: u32) -> Result<(), UmError> {
+        self.memory.load_segment(index)
+    }
+
+    fn map(&mut self, length: u32) -> Result<u32, UmError> {
+        self.memory.map(length)
+    }
+
+    fn unmap(&mut self, index: u32) -> Result<(), UmError> {
+        self.memory.unmap(index)
+    }
+
+    fn segment(&self, index: u32) -> Result<&[u32], UmError> {
+        self.memory.segment(index)
+    }
+
+    fn segment_mut(&mut self, index: u32) -> Result<&mut [u32], UmError> {
+        self.memory.segment_mut(index)
+    }
+
+    fn live_segments(&self) -> usize {
+        self.memory.live_segments()
+    }
+
+    fn mapped_ids(&self) -> Vec<u32> {
+        self.memory.mapped_ids()
+    }
+
+    fn free_ids(&self) -> Vec<u32> {
+        self.memory.free_ids()
+    }
+
+    fn restore(&mut self, segments: Vec<(u32, Vec<u32>)>, free_ids: Vec<u32>) {
+        self.memory.restore(segments, free_ids);
+    }
+
+    fn load(&self, segment_index: u32, offset: u32) -> Result<u32, UmError> {
+        let value = self.memory.load(segment_index, offset)?;
+        let mut hooks = self.hooks.borrow_mut();
+        if !hooks.is_empty() {
+            let access = Access {
+                segment: segment_index,
+                offset,
+                kind: AccessKind::Read(value),
+            };
+            Self::notify(&mut hooks, access);
+        }
+        Ok(value)
+    }
+
+    fn store(&mut self, value: u32, segment_index: u32, offset: u32) -> Result<(), UmError> {
+        let hooks = self.hooks.get_mut();
+        if !hooks
+            .iter()
+            .any(|hook| hook.watches(false, segment_index, offset))
+        {
+            return self.memory.store(value, segment_index, offset);
+        }
+        let old = self.memory.load(segment_index, offset)?;
+        self.memory.store(value, segment_index, offset)?;
+        let access = Access {
+            segment: segment_index,
+            offset,
+            kind: AccessKind::Write { old, new: value },
+        };
+        Self::notify(hooks, access);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rumasm::rumasm::{halt, load, loadv, map, store};
+
+    use super::*;
+    use crate::{
+        rumio::Streams,
+        rummem::{BTreeMemory, FreeListMemory},
+        rumrun::Machine,
+    };
+
+    /// Memory holding a program and one mapped segment of 4 words, and a log
+    /// that hooks can record accesses in.
+    fn watched<M: SegmentStore>() -> (Watched<M>, u32, Rc<RefCell<Vec<Access>>>) {
+        let mut memory = Watched::new(M::default());
+        memory.load_program(vec![0; 2]);
+        let index = memory.map(4).unwrap();
+        (memory, index, Rc::new(RefCell::new(vec![])))
+    }
+
+    fn calls_hooks<M: SegmentStore>() {
+        let (mut memory, index, log) = watched::<M>();
+        let reads = Rc::clone(&log);
+        memory.on_read(index, 1..3, move |access| reads.borrow_mut().push(access));
+        let writes = Rc::clone(&log);
+        let id = memory.on_write(index, 2..4, move |access| writes.borrow_mut().push(access));
+
+        memory.store(5, index, 1).unwrap();
+        memory.store(6, index, 2).unwrap();
+        memory.store(7, index, 2).unwrap();
+        assert_eq!(memory.load(index, 0), Ok(0));
+        assert_eq!(memory.load(index, 1), Ok(5));
+        assert_eq!(memory.load(0, 1), Ok(0));
+        // Faulting accesses are not reported
+        assert!(memory.store(8, index, 4).is_err());
+
+        assert!(memory.unhook(id));
+        assert!(!memory.unhook(id));
+        memory.store(9, index, 3).unwrap();
+
+        let access = |offset, kind| Access {
+            segment: index,
+            offset,
+            kind,
+        };
+        assert_eq!(
+            *log.borrow(),
+            [
+                access(2, AccessKind::Write { old: 0, new: 6 }),
+                access(2, AccessKind::Write { old: 6, new: 7 }),
+                access(1, AccessKind::Read(5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn btree_hooks() {
+        calls_hooks::<BTreeMemory>();
+    }
+
+    #[test]
+    fn freelist_hooks() {
+        calls_hooks::<FreeListMemory>();
+    }
+
+    #[test]
+    fn watches_machine_accesses() {
+        let (mut memory, _, log) = watched::<FreeListMemory>();
+        // The program maps segment 2, stores 42 at offset 1, then loads it
+        memory.load_program(vec![
+            loadv(1, 3),
+            map(2, 1),
+            loadv(3, 1),
+            loadv(4, 42),
+            store(2, 3, 4),
+            load(5, 2, 3),
+            halt(),
+        ]);
+        let reads = Rc::clone(&log);
+        memory.on_read(2, 0..u32::MAX, move |access| {
+            reads.borrow_mut().push(access)
+        });
+        let writes = Rc::clone(&log);
+        memory.on_write(2, 1..2, move |access| writes.borrow_mut().push(access));
+
+        let mut machine = Machine::with_io(memory, Streams::new(&b""[..], Vec::new()));
+        machine.run().unwrap();
+        let kinds: Vec<AccessKind> = log.borrow().iter().map(|access| access.kind).collect();
+        assert_eq!(
+            kinds,
+            [AccessKind::Write { old: 0, new: 42 }, AccessKind::Read(42)]
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Write},
+};
+
+use serde::Serialize;
+
+use crate::{
+    rumdis::{self, Operation},
+    rumio::IoDevice,
+    rummem::SegmentStore,
+    rumrun::{Machine, Observer, Registers},
+};
+
+/// Counts what a program does while it runs: which operations it executes,
+/// where in segment 0 it spends its time, and how it uses memory.
+#[derive(Default)]
+pub struct Profiler {
+    opcodes: [u64; 16],
+    /// Executions per segment 0 offset, across every program loaded there.
+    pcs: Vec<u64>,
+    load_program_copies: u64,
+    load_program_jumps: u64,
+    maps: u64,
+    unmaps: u64,
+    peak_live_segments: usize,
+    words_allocated: u64,
+    /// The program counter, word and registers of the instruction being
+    /// executed. It is only counted once it completes, as an Input waiting
+    /// for input is executed again.
+    pending: Option<(usize, u32, Registers)>,
+}
+
+/// How often a single segment 0 offset was executed.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct HotSpot {
+    pub pc: usize,
+    pub count: u64,
+    /// The instruction at `pc` when the report was made.
+    pub instruction: String,
+}
+
+/// A summary of a profiled run.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub instructions: u64,
+    /// Executions of each operation, by `rumdis` mnemonic.
+    pub opcodes: BTreeMap<&'static str, u64>,
+    /// The most frequently executed segment 0 offsets, hottest first.
+    pub hot_spots: Vec<HotSpot>,
+    /// LoadProgram instructions that replaced segment 0 with another segment.
+    pub load_program_copies: u64,
+    /// LoadProgram instructions from segment 0 itself, i.e. jumps.
+    pub load_program_jumps: u64,
+    pub maps: u64,
+    pub unmaps: u64,
+    /// The most segments (including segment 0) mapped at once.
+    pub peak_live_segments: usize,
+    /// Total length of every segment mapped, in words.
+    pub words_allocated: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Summarize the run so far.
+    ///
+    /// # Arguments
+    /// - `machine`: the profiled machine, used to disassemble hot spots
+    /// - `hot_spots`: how many of the hottest program counter values to report
+    pub fn report<D: IoDevice, M: SegmentStore>(
+        &self,
+        machine: &Machine<D, M>,
+        hot_spots: usize,
+    ) -> Report {
+        let opcodes = (0..14)
+            .filter_map(Operation::from_opcode)
+            .map(|op| (op.mnemonic(), self.opcodes[op as usize]))
+            .collect();
+
+        let mut hottest: Vec<(usize, u64)> = self
+            .pcs
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let program = machine.memory().program();
+        let hot_spots = hottest
+            .into_iter()
+            .take(hot_spots)
+            .map(|(pc, count)| HotSpot {
+                pc,
+                count,
+                instruction: match program.get(pc) {
+                    Some(word) => rumdis::disassemble(word).to_string(),
+                    None => String::from("<outside segment 0>"),
+                },
+            })
+            .collect();
+
+        Report {
+            instructions: self.opcodes.iter().sum(),
+            opcodes,
+            hot_spots,
+            load_program_copies: self.load_program_copies,
+            load_program_jumps: self.load_program_jumps,
+            maps: self.maps,
+            unmaps: self.unmaps,
+            peak_live_segments: self.peak_live_segments,
+            words_allocated: self.words_allocated,
+        }
+    }
+}
+
+impl Observer for Profiler {
+    fn before<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        self.pending = machine
+            .current_word()
+            .map(|word| (machine.program_counter(), word, *machine.registers()));
+    }
+
+    fn after<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        let (pc, word, registers) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let instruction = rumdis::disassemble(&word);
+
+        if pc >= self.pcs.len() {
+            self.pcs
+                .resize(machine.memory().get_program_length().max(pc + 1), 0);
+        }
+        self.pcs[pc] += 1;
+        self.opcodes[instruction.opcode as usize] += 1;
+
+        let mut mapping = false;
+        match instruction.operation() {
+            Some(Operation::LoadProgram) if registers[instruction.reg_b as usize] == 0 => {
+                self.load_program_jumps += 1
+            }
+            Some(Operation::LoadProgram) => self.load_program_copies += 1,
+            Some(Operation::Map) => {
+                self.maps += 1;
+                self.words_allocated += registers[instruction.reg_c as usize] as u64;
+                mapping = true;
+            }
+            Some(Operation::Unmap) => self.unmaps += 1,
+            _ => {}
+        }
+
+        if mapping || self.peak_live_segments == 0 {
+            self.peak_live_segments = self
+                .peak_live_segments
+                .max(machine.memory().live_segments());
+        }
+    }
+}
+
+impl Report {
+    /// Write the report as pretty-printed JSON.
+    pub fn write_json(&self, out: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(out, self)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    /// Format the report as a human-readable table.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<24}{:>16}", "instructions", self.instructions)?;
+        writeln!(f)?;
+        writeln!(f, "{:<24}{:>16}{:>9}", "operation", "count", "%")?;
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1));
+        for (mnemonic, count) in opcodes {
+            writeln!(
+                f,
+                "{:<24}{:>16}{:>8.2}%",
+                mnemonic,
+                count,
+                percent(*count, self.instructions)
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<24}{:>16}{:>9}  instruction",
+            "hot spot (pc)", "count", "%"
+        )?;
+        for spot in &self.hot_spots {
+            writeln!(
+                f,
+                "{:<24}{:>16}{:>8.2}%  {}",
+                spot.pc,
+                spot.count,
+                percent(spot.count, self.instructions),
+                spot.instruction
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<24}{:>16}", "loadp copies", self.load_program_copies)?;
+        writeln!(f, "{:<24}{:>16}", "loadp jumps", self.load_program_jumps)?;
+        writeln!(f, "{:<24}{:>16}", "maps", self.maps)?;
+        writeln!(f, "{:<24}{:>16}", "unmaps", self.unmaps)?;
+        writeln!(
+            f,
+            "{:<24}{:>16}",
+            "peak live segments", self.peak_live_segments
+        )?;
+        write!(f, "{:<24}{:>16}", "words allocated", self.words_allocated)
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * count as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rumio::Slow;
+
+    // 0: loadv r1, 3
+    // 1: map r2, r1
+    // 2: map r3, r1
+    // 3: unmap r2
+    // 4: loadv r4, 6
+    // 5: loadp r0, r4 (jump to 6)
+    // 6: halt
+    const PROGRAM: [u32; 7] = [
+        0xD200_0003,
+        0x8000_0011,
+        0x8000_0019,
+        0x9000_0002,
+        0xD800_0006,
+        0xC000_0004,
+        0x7000_0000,
+    ];
+
+    #[test]
+    fn profile_counts() {
+        let mut machine = Machine::from_program(PROGRAM.to_vec(), &b""[..], Vec::new());
+        let mut profiler = Profiler::new();
+        machine.run_observed(None, &mut profiler).unwrap();
+        let report = profiler.report(&machine, 2);
+
+        assert_eq!(report.instructions, 7);
+        assert_eq!(report.opcodes["map"], 2);
+        assert_eq!(report.opcodes["loadv"], 2);
+        assert_eq!(report.opcodes["add"], 0);
+        assert_eq!(report.load_program_jumps, 1);
+        assert_eq!(report.load_program_copies, 0);
+        assert_eq!(report.maps, 2);
+        assert_eq!(report.unmaps, 1);
+        assert_eq!(report.peak_live_segments, 3);
+        assert_eq!(report.words_allocated, 6);
+        assert_eq!(
+            report.hot_spots[0],
+            HotSpot {
+                pc: 0,
+                count: 1,
+                instruction: String::from("loadv r1, 3")
+            }
+        );
+    }
+
+    #[test]
+    fn counts_waiting_input_once() {
+        let mut memory = crate::rummem::Memory::new();
+        // in r1; halt
+        memory.load_program(vec![0xB000_0001, 0x7000_0000]);
+        let mut machine = Machine::with_io(memory, Slow(3));
+        let mut profiler = Profiler::new();
+        machine.run_observed(None, &mut profiler).unwrap();
+        let report = profiler.report(&machine, 2);
+
+        assert_eq!(report.instructions, 2);
+        assert_eq!(report.opcodes["input"], 1);
+        assert_eq!(report.hot_spots[0].count, 1);
+    }
+
+    #[test]
+    fn report_formats() {
+        let mut machine = Machine::from_program(PROGRAM.to_vec(), &b""[..], Vec::new());
+        let mut profiler = Profiler::new();
+        machine.run_observed(None, &mut profiler).unwrap();
+        let report = profiler.report(&machine, 10);
+
+        let table = report.to_string();
+        assert!(table.contains("peak live segments"));
+        assert!(table
+            .lines()
+            .any(|l| l.starts_with("map") && l.contains("28.57%")));
+
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["opcodes"]["unmap"], 1);
+        assert_eq!(value["hot_spots"].as_array().unwrap().len(), 7);
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+};
+
+use crate::rumio::IoDevice;
+
+/// Magic bytes at the start of a session log.
+const MAGIC: &[u8; 4] = b"UMIO";
+/// Version of the session log format.
+const VERSION: u8 = 1;
+
+/// A single Input or Output instruction's transfer.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Event {
+    /// A byte read by an Input instruction, or `None` at the end of input.
+    Input(Option<u8>),
+    /// A byte written by an Output instruction.
+    Output(u8),
+}
+
+/// An event, and the number of instructions executed before it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Entry {
+    pub step: u64,
+    pub event: Event,
+}
+
+/// The first point at which a replayed run differed from its recording.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Divergence {
+    /// The next recorded entry, or `None` if the recording had ended.
+    pub expected: Option<Entry>,
+    /// What the replayed run did instead, or `None` if it stopped early.
+    pub actual: Option<Entry>,
+}
+
+/// Records every byte passing through an `IoDevice`, and when it passed, to a session log.
+///
+/// Write errors are remembered rather than interrupting the machine, and are
+/// reported by `finish`.
+pub struct Recorder<D: IoDevice, W: Write> {
+    device: D,
+    log: W,
+    error: Option<io::Error>,
+}
+
+impl<D: IoDevice, W: Write> Recorder<D, W> {
+    pub fn new(device: D, log: W) -> Recorder<D, W> {
+        let mut recorder = Recorder {
+            device,
+            log,
+            error: None,
+        };
+        recorder.write(|log| {
+            log.write_all(MAGIC)?;
+            log.write_all(&[VERSION])
+        });
+        recorder
+    }
+
+    fn write(&mut self, f: impl FnOnce(&mut W) -> io::Result<()>) {
+        if self.error.is_none() {
+            if let Err(e) = f(&mut self.log) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Flush the session log.
+    ///
+    /// # Errors
+    /// The first error encountered while writing the log
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.log.flush()
+    }
+}
+
+impl<D: IoDevice, W: Write> IoDevice for Recorder<D, W> {
+    fn output(&mut self, value: u8) -> io::Result<()> {
+        self.device.output(value)
+    }
+
+    fn input(&mut self) -> io::Result<Option<u8>> {
+        self.device.input()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+
+    fn output_at(&mut self, step: u64, value: u8) -> io::Result<()> {
+        let entry = Entry {
+            step,
+            event: Event::Output(value),
+        };
+        self.write(|log| write_entry(log, &entry));
+        self.device.output_at(step, value)
+    }
+
+    fn input_at(&mut self, step: u64) -> io::Result<Option<u8>> {
+        let value = self.device.input_at(step)?;
+        let entry = Entry {
+            step,
+            event: Event::Input(value),
+        };
+        // Flush so the log survives an interrupted interactive session
+        self.write(|log| {
+            write_entry(log, &entry)?;
+            log.flush()
+        });
+        Ok(value)
+    }
+}
+
+/// Feeds a recorded session's input back to a machine, checking that it
+/// produces the recorded output at the recorded points.
+///
+/// Output is still sent to the wrapped device. After a divergence the
+/// remaining recorded input is fed in order, regardless of when it is asked for.
+pub struct Replay<D: IoDevice> {
+    device: D,
+    entries: Vec<Entry>,
+    next: usize,
+    divergence: Option<Divergence>,
+}
+
+impl<D: IoDevice> Replay<D> {
+    /// # Arguments
+    /// - `entries`: a recorded session, as returned by `read`
+    /// - `device`: where output is sent
+    pub fn new(entries: Vec<Entry>, device: D) -> Replay<D> {
+        Replay {
+            device,
+            entries,
+            next: 0,
+            divergence: None,
+        }
+    }
+
+    /// Get the first divergence so far, if any.
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.divergence
+    }
+
+    /// End the replay, checking that the whole recording was used.
+    ///
+    /// # Returns
+    /// The first divergence, or `None` if the run matched the recording
+    pub fn finish(&mut self) -> Option<Divergence> {
+        if self.divergence.is_none() && self.next < self.entries.len() {
+            self.diverge(None);
+        }
+        self.divergence
+    }
+
+    fn diverge(&mut self, actual: Option<Entry>) {
+        if self.divergence.is_none() {
+            self.divergence = Some(Divergence {
+                expected: self.entries.get(self.next).copied(),
+                actual,
+            });
+        }
+    }
+}
+
+impl<D: IoDevice> IoDevice for Replay<D> {
+    fn output(&mut self, value: u8) -> io::Result<()> {
+        self.device.output(value)
+    }
+
+    fn input(&mut self) -> io::Result<Option<u8>> {
+        self.input_at(0)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+
+    fn output_at(&mut self, step: u64, value: u8) -> io::Result<()> {
+        let actual = Entry {
+            step,
+            event: Event::Output(value),
+        };
+        match self.entries.get(self.next) {
+            Some(&expected) if expected == actual => self.next += 1,
+            Some(Entry {
+                event: Event::Output(_),
+                ..
+            }) => {
+                self.diverge(Some(actual));
+                self.next += 1;
+            }
+            _ => self.diverge(Some(actual)),
+        }
+        self.device.output_at(step, value)
+    }
+
+    fn input_at(&mut self, step: u64) -> io::Result<Option<u8>> {
+        // Feed the next recorded input, skipping any output recorded before it
+        let found = self.entries[self.next..]
+            .iter()
+            .enumerate()
+            .find_map(|(i, entry)| match entry.event {
+                Event::Input(value) => Some((i, value)),
+                Event::Output(_) => None,
+            });
+        let (skipped, value) = found.unwrap_or((self.entries.len() - self.next, None));
+        let actual = Entry {
+            step,
+            event: Event::Input(value),
+        };
+        if skipped != 0 || self.entries.get(self.next) != Some(&actual) {
+            self.diverge(Some(actual));
+        }
+        self.next = (self.next + skipped + 1).min(self.entries.len());
+        Ok(value)
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Input(Some(value)) => write!(f, "input {:?}", *value as char),
+            Event::Input(None) => write!(f, "end of input"),
+            Event::Output(value) => write!(f, "output {:?}", *value as char),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at step {}", self.event, self.step)
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected {
+            Some(expected) => write!(f, "expected {}", expected)?,
+            None => write!(f, "expected the end of the session")?,
+        }
+        match self.actual {
+            Some(actual) => write!(f, ", but got {}", actual),
+            None => write!(f, ", but the program stopped"),
+        }
+    }
+}
+
+/// Write an entry in the session log format: a kind byte (`i` for input,
+/// `e` for end of input, `o` for output), the step as 8 big-endian bytes,
+/// then the byte transferred, if any.
+fn write_entry(log: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    let (kind, value) = match entry.event {
+        Event::Input(Some(value)) => (b'i', Some(value)),
+        Event::Input(None) => (b'e', None),
+        Event::Output(value) => (b'o', Some(value)),
+    };
+    log.write_all(&[kind])?;
+    log.write_all(&entry.step.to_be_bytes())?;
+    if let Some(value) = value {
+        log.write_all(&[value])?;
+    }
+    Ok(())
+}
+
+/// Read a session log written by a `Recorder`.
+///
+/// # Errors
+/// - `InvalidData` if the log does not start with the expected header or has an unknown entry
+/// - Any error from `input`, including `UnexpectedEof` for a truncated entry
+pub fn read(mut input: impl Read) -> io::Result<Vec<Entry>> {
+    let mut header = [0_u8; 5];
+    input.read_exact(&mut header)?;
+    if &header[..4] != MAGIC || header[4] != VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a version 1 UM session log",
+        ));
+    }
+
+    let mut entries = vec![];
+    loop {
+        let mut kind = [0_u8; 1];
+        match input.read_exact(&mut kind) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(entries),
+            Err(e) => return Err(e),
+        }
+        let mut step = [0_u8; 8];
+        input.read_exact(&mut step)?;
+        let mut value = [0_u8; 1];
+        let event = match kind[0] {
+            b'e' => Event::Input(None),
+            b'i' | b'o' => {
+                input.read_exact(&mut value)?;
+                if kind[0] == b'i' {
+                    Event::Input(Some(value[0]))
+                } else {
+                    Event::Output(value[0])
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "unknown session log entry",
+                ))
+            }
+        };
+        entries.push(Entry {
+            step: u64::from_be_bytes(step),
+            event,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        rumio::Streams,
+        rummem::{self, SegmentStore},
+        rumrun::Machine,
+    };
+
+    // 0: input r1
+    // 1: output r1
+    // 2: input r1
+    // 3: output r1
+    // 4: halt
+    const ECHO: [u32; 5] = [
+        0xB000_0001,
+        0xA000_0001,
+        0xB000_0001,
+        0xA000_0001,
+        0x7000_0000,
+    ];
+
+    fn record(program: &[u32], input: &'static [u8]) -> Vec<u8> {
+        let device = Recorder::new(Streams::new(input, Vec::new()), Vec::new());
+        let mut memory = rummem::Memory::default();
+        memory.load_program(program.to_vec());
+        let mut machine = Machine::with_io(memory, device);
+        machine.run().unwrap();
+        machine.io_mut().finish().unwrap();
+        machine.into_io().log
+    }
+
+    fn replay(program: &[u32], log: &[u8]) -> (Vec<u8>, Option<Divergence>) {
+        let entries = read(log).unwrap();
+        // Replayed input never comes from the wrapped device
+        let device = Replay::new(entries, Streams::new(&b"unused"[..], Vec::new()));
+        let mut memory = rummem::Memory::default();
+        memory.load_program(program.to_vec());
+        let mut machine = Machine::with_io(memory, device);
+        let _ = machine.run();
+        let divergence = machine.io_mut().finish();
+        (machine.into_io().device.output, divergence)
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let log = record(&ECHO, b"ok");
+        assert_eq!(
+            read(&log[..]).unwrap(),
+            vec![
+                Entry {
+                    step: 0,
+                    event: Event::Input(Some(b'o'))
+                },
+                Entry {
+                    step: 1,
+                    event: Event::Output(b'o')
+                },
+                Entry {
+                    step: 2,
+                    event: Event::Input(Some(b'k'))
+                },
+                Entry {
+                    step: 3,
+                    event: Event::Output(b'k')
+                },
+            ]
+        );
+        assert_eq!(replay(&ECHO, &log), (b"ok".to_vec(), None));
+    }
+
+    #[test]
+    fn detects_divergence() {
+        let log = record(&ECHO, b"ok");
+
+        // Output the input plus one instead: loadv r2, 1; add r1, r1, r2
+        let mut changed = ECHO.to_vec();
+        changed.splice(1..1, [0xD400_0001, 0x3000_004A]);
+        let (output, divergence) = replay(&changed, &log);
+        assert_eq!(output, b"pk");
+        assert_eq!(
+            divergence.unwrap().to_string(),
+            "expected output 'o' at step 1, but got output 'p' at step 3"
+        );
+
+        // Stop after the first byte
+        let (_, divergence) = replay(&ECHO[..2], &log);
+        assert_eq!(
+            divergence,
+            Some(Divergence {
+                expected: Some(Entry {
+                    step: 2,
+                    event: Event::Input(Some(b'k'))
+                }),
+                actual: None
+            })
+        );
+    }
+}
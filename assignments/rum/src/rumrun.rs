@@ -1,121 +1,752 @@
+use std::io::{ErrorKind, Read, Stdin, Stdout, Write};
+
+mod fuse;
+
+pub use fuse::Engine;
+
 use crate::{
-    rumdis, rumio,
-    rummem::{self, get_program_length},
+    rumdis::{self, Operation},
+    rumerr::{Fault, UmError},
+    rumio::{self, IoDevice, Streams},
+    rummem::{self, SegmentStore},
 };
-use num_derive::FromPrimitive;
-use num_traits::FromPrimitive;
-
-type Registers = [u32; 8];
-
-#[derive(FromPrimitive)]
-#[repr(u32)]
-enum Operation {
-    ConditionalMove,
-    LoadSegment,
-    StoreSegment,
-    Add,
-    Multiply,
-    Divide,
-    NAND,
-    Halt,
-    Map,
-    Unmap,
-    Output,
-    Input,
-    LoadProgram,
-    LoadValue,
+
+pub type Registers = [u32; 8];
+
+/// The result of executing a single instruction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Status {
+    /// The machine can continue executing.
+    Running,
+    /// The machine has halted and will not execute any further instructions.
+    Halted,
+    /// An Input instruction found no input available yet. The program counter
+    /// still points at it, so it will be retried by the next step.
+    WaitingForInput,
 }
 
-pub fn execute(mut memory: &mut rummem::Memory) {
-    let mut program_counter = 0_usize;
-    let mut program_length = rummem::get_program_length(&memory);
-    let mut registers: Registers = [0; 8];
-
-    while program_counter != program_length {
-        // Get next instruction
-        let rumdis::Instruction {
-            opcode,
-            reg_a,
-            reg_b,
-            reg_c,
-            reg_load,
-            load_value,
-        } = rumdis::disassemble(&memory[&0][program_counter as usize]);
-
-        // match FromPrimitive::from_u32(opcode) {
-        //     Some(Operation::LoadValue) => {
-        //         println!(
-        //             "[{}] op: {}, ra: {}, rb: {}, rc: {}, val: {}",
-        //             program_counter, opcode, reg_load, 0, 0, load_value
-        //         );
-        //     }
-        //     _ => {
-        //         println!(
-        //             "[{}] op: {}, ra: {}, rb: {}, rc: {}, val: {}",
-        //             program_counter, opcode, reg_a, reg_b, reg_c, 0
-        //         );
-        //     }
-        // }
+/// Why `Machine::run_for` returned.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+    /// The machine halted.
+    Halted,
+    /// The instruction budget ran out before the machine halted.
+    Yielded,
+    /// The machine is blocked on an Input instruction.
+    WaitingForInput,
+}
+
+/// A Universal Machine that can be stepped one instruction at a time.
+///
+/// The machine owns its registers, memory and program counter, and performs
+/// all I/O through the `IoDevice` it is created with. Memory is kept in any
+/// `SegmentStore` backend, `rummem::Memory` unless another is chosen.
+pub struct Machine<D: IoDevice = Streams<Stdin, Stdout>, M: SegmentStore = rummem::Memory> {
+    memory: M,
+    /// Segment 0, decoded once and kept in step with every change to it.
+    program: Vec<rumdis::Decoded>,
+    /// Segment 0 as superinstructions, also kept in step, when using `Engine::Fused`.
+    fused: Option<Vec<Option<fuse::Fusion>>>,
+    registers: Registers,
+    program_counter: usize,
+    halted: bool,
+    steps: u64,
+    /// Whether Input at the end of input faults instead of loading all ones.
+    strict_eof: bool,
+    io: D,
+}
+
+impl<M: SegmentStore> Machine<Streams<Stdin, Stdout>, M> {
+    /// Create a machine attached to stdin and stdout whose segment 0 has
+    /// already been loaded into `memory`.
+    pub fn new(memory: M) -> Machine<Streams<Stdin, Stdout>, M> {
+        Machine::with_io(memory, rumio::stdio())
+    }
+}
+
+impl<R: Read, W: Write> Machine<Streams<R, W>> {
+    /// Create a machine that runs `program` against the given input and output streams.
+    ///
+    /// # Arguments
+    /// - `program`: a `Vec` of UM words to load into segment 0
+    /// - `input`: where Input instructions read from
+    /// - `output`: where Output instructions write to
+    pub fn from_program(program: Vec<u32>, input: R, output: W) -> Machine<Streams<R, W>> {
+        let mut memory = rummem::Memory::new();
+        memory.load_program(program);
+        Machine::with_io(memory, Streams::new(input, output))
+    }
+}
+
+impl<D: IoDevice, M: SegmentStore> Machine<D, M> {
+    /// Create a machine attached to `io` whose segment 0 has already been loaded into `memory`.
+    pub fn with_io(memory: M, io: D) -> Machine<D, M> {
+        Machine {
+            program: decode_program(memory.program()),
+            fused: None,
+            memory,
+            registers: [0; 8],
+            program_counter: 0,
+            halted: false,
+            steps: 0,
+            strict_eof: false,
+            io,
+        }
+    }
+
+    /// Create a machine that resumes from a saved state, e.g. a `rumsnap` snapshot.
+    ///
+    /// # Arguments
+    /// - `memory`: the saved memory, including segment 0
+    /// - `io`: the I/O device to continue with
+    /// - `registers`, `program_counter`, `steps`, `halted`: the saved machine state
+    pub fn from_state(
+        memory: M,
+        io: D,
+        registers: Registers,
+        program_counter: usize,
+        steps: u64,
+        halted: bool,
+    ) -> Machine<D, M> {
+        Machine {
+            registers,
+            program_counter,
+            steps,
+            halted,
+            ..Machine::with_io(memory, io)
+        }
+    }
+
+    /// Return the machine to an earlier state, e.g. a `rumsnap` snapshot,
+    /// keeping its I/O device and settings.
+    ///
+    /// # Arguments
+    /// - `segments`, `free_ids`: the saved memory, as for `SegmentStore::restore`
+    /// - `registers`, `program_counter`, `steps`, `halted`: the saved machine state
+    pub fn set_state(
+        &mut self,
+        segments: Vec<(u32, Vec<u32>)>,
+        free_ids: Vec<u32>,
+        registers: Registers,
+        program_counter: usize,
+        steps: u64,
+        halted: bool,
+    ) {
+        self.memory.restore(segments, free_ids);
+        self.program = decode_program(self.memory.program());
+        if self.fused.is_some() {
+            self.fused = Some(fuse::fuse(&self.program));
+        }
+        self.registers = registers;
+        self.program_counter = program_counter;
+        self.steps = steps;
+        self.halted = halted;
+    }
+
+    /// Replace the machine's I/O device with one built from it, e.g. to wrap
+    /// it, keeping the rest of the machine.
+    pub fn map_io<E: IoDevice>(self, wrap: impl FnOnce(D) -> E) -> Machine<E, M> {
+        Machine {
+            memory: self.memory,
+            program: self.program,
+            fused: self.fused,
+            registers: self.registers,
+            program_counter: self.program_counter,
+            halted: self.halted,
+            steps: self.steps,
+            strict_eof: self.strict_eof,
+            io: wrap(self.io),
+        }
+    }
+
+    pub fn io(&self) -> &D {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut D {
+        &mut self.io
+    }
+
+    /// Consume the machine, returning its I/O device (e.g. to inspect captured output).
+    pub fn into_io(self) -> D {
+        self.io
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    /// Choose what Input does at the end of input: load 0xFFFFFFFF into
+    /// register C as the spec requires (the default), or, if `strict` is
+    /// set, fault with `InputEof` so tests catch programs that read too far.
+    pub fn set_strict_eof(&mut self, strict: bool) {
+        self.strict_eof = strict;
+    }
+
+    /// Choose how `run` and `run_for` execute instructions. Single steps,
+    /// and runs with an observer, always use the plain interpreter.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.fused = match engine {
+            Engine::Plain => None,
+            Engine::Fused => Some(fuse::fuse(&self.program)),
+        };
+    }
+
+    pub fn engine(&self) -> Engine {
+        match self.fused {
+            Some(_) => Engine::Fused,
+            None => Engine::Plain,
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Get the number of instructions executed so far, including Halt.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Get the instruction that will be executed by the next call to `step`.
+    ///
+    /// # Returns
+    /// The decoded instruction, or `None` if the program counter is outside segment 0
+    pub fn current_instruction(&self) -> Option<rumdis::Instruction> {
+        self.current_word().map(|word| rumdis::disassemble(&word))
+    }
+
+    /// Get the raw word that will be executed by the next call to `step`.
+    pub fn current_word(&self) -> Option<u32> {
+        self.memory.program().get(self.program_counter).copied()
+    }
+
+    /// Build a `Fault` for `error` at the current program counter.
+    fn fault(&self, error: UmError) -> Fault {
+        Fault {
+            error,
+            program_counter: self.program_counter,
+            instruction: self.current_word(),
+        }
+    }
+
+    /// Execute a single instruction.
+    ///
+    /// # Returns
+    /// The status of the machine after the instruction has executed
+    ///
+    /// # Errors
+    /// A `Fault` if the instruction fails. The machine is left as it was
+    /// before the faulting instruction, with the program counter pointing at it.
+    pub fn step(&mut self) -> Result<Status, Fault> {
+        if self.halted {
+            return Ok(Status::Halted);
+        }
+        let result = match self.program.get(self.program_counter) {
+            Some(&decoded) => self.execute(decoded),
+            None => Err(UmError::PcOutOfRange {
+                length: self.memory.get_program_length(),
+            }),
+        };
+        self.complete(result, 1)
+    }
+
+    /// Finish executing `length` instructions: count them if they completed,
+    /// or turn an error into a `Fault` at the current program counter.
+    #[inline(always)]
+    fn complete(&mut self, result: Result<Status, UmError>, length: u64) -> Result<Status, Fault> {
+        let status = result.map_err(|error| {
+            // Let buffered output reach its destination before the fault is reported
+            let _ = self.io.flush();
+            self.fault(error)
+        })?;
+        if status != Status::WaitingForInput {
+            self.steps += length;
+        }
+        Ok(status)
+    }
+
+    /// Execute a decoded instruction at the current program counter.
+    #[inline(always)]
+    fn execute(&mut self, decoded: rumdis::Decoded) -> Result<Status, UmError> {
+        let memory = &mut self.memory;
+        let registers = &mut self.registers;
+        let rumdis::Decoded {
+            operation,
+            a,
+            b,
+            c,
+            value,
+        } = decoded;
+        let (a, b, c) = (a as usize, b as usize, c as usize);
 
         // Execute instruction
-        match FromPrimitive::from_u32(opcode) {
+        match operation {
             Some(Operation::ConditionalMove) => {
-                if registers[reg_c as usize] != 0 {
-                    registers[reg_a as usize] = registers[reg_b as usize];
+                if registers[c] != 0 {
+                    registers[a] = registers[b];
                 }
             }
             Some(Operation::LoadSegment) => {
-                registers[reg_a as usize] =
-                    memory[&registers[reg_b as usize]][registers[reg_c as usize] as usize];
+                registers[a] = memory.load(registers[b], registers[c])?;
             }
             Some(Operation::StoreSegment) => {
-                memory.get_mut(&registers[reg_a as usize]).unwrap()
-                    [registers[reg_b as usize] as usize] = registers[reg_c as usize];
+                let (segment, offset, value) = (registers[a], registers[b], registers[c]);
+                self.store(segment, offset, value)?;
             }
             Some(Operation::Add) => {
-                registers[reg_a as usize] =
-                    u32::wrapping_add(registers[reg_b as usize], registers[reg_c as usize]);
+                registers[a] = u32::wrapping_add(registers[b], registers[c]);
             }
             Some(Operation::Multiply) => {
-                registers[reg_a as usize] =
-                    u32::wrapping_mul(registers[reg_b as usize], registers[reg_c as usize]);
+                registers[a] = u32::wrapping_mul(registers[b], registers[c]);
             }
             Some(Operation::Divide) => {
-                registers[reg_a as usize] = registers[reg_b as usize] / registers[reg_c as usize];
+                registers[a] = registers[b]
+                    .checked_div(registers[c])
+                    .ok_or(UmError::DivideByZero)?;
             }
-            Some(Operation::NAND) => {
-                registers[reg_a as usize] =
-                    !(registers[reg_b as usize] & registers[reg_c as usize]);
+            Some(Operation::Nand) => {
+                registers[a] = !(registers[b] & registers[c]);
             }
             Some(Operation::Halt) => {
-                return;
+                self.halted = true;
+                let _ = self.io.flush();
+                return Ok(Status::Halted);
             }
             Some(Operation::Map) => {
-                registers[reg_b as usize] = rummem::map(&mut memory, registers[reg_c as usize]);
+                registers[b] = memory.map(registers[c])?;
             }
             Some(Operation::Unmap) => {
-                rummem::unmap(&mut memory, registers[reg_c as usize]);
+                memory.unmap(registers[c])?;
             }
             Some(Operation::Output) => {
-                rumio::output(registers[reg_c as usize] as u8);
+                // A closed output device does not stop the machine
+                let _ = self.io.output_at(self.steps, registers[c] as u8);
             }
             Some(Operation::Input) => {
-                registers[reg_c as usize] = rumio::input() as u32;
+                let value = match self.io.input_at(self.steps) {
+                    Ok(value) => value,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        return Ok(Status::WaitingForInput);
+                    }
+                    Err(_) => None,
+                };
+                registers[c] = match value {
+                    Some(value) => value as u32,
+                    None if self.strict_eof => return Err(UmError::InputEof),
+                    None => u32::MAX,
+                };
             }
             Some(Operation::LoadProgram) => {
-                let program = memory[&registers[reg_b as usize]].clone();
-                rummem::load_program(&mut memory, program);
-                program_counter = registers[reg_c as usize] as usize;
-                program_length = get_program_length(memory);
+                // Loading segment 0 into itself is just a jump
+                if registers[b] != 0 {
+                    memory.load_segment(registers[b])?;
+                    self.program = decode_program(memory.program());
+                    if let Some(fused) = &mut self.fused {
+                        *fused = fuse::fuse(&self.program);
+                    }
+                }
+                self.program_counter = registers[c] as usize;
                 // Avoid incrementing the program counter
-                continue;
+                return Ok(Status::Running);
             }
             Some(Operation::LoadValue) => {
-                registers[reg_load as usize] = load_value;
+                registers[a] = value;
+            }
+            None => {
+                let opcode = memory.program()[self.program_counter] >> 28;
+                return Err(UmError::InvalidOpcode(opcode));
+            }
+        }
+
+        self.program_counter += 1;
+        Ok(Status::Running)
+    }
+
+    /// Store `value` at `offset` in `segment`, as StoreSegment does.
+    #[inline(always)]
+    fn store(&mut self, segment: u32, offset: u32, value: u32) -> Result<(), UmError> {
+        self.memory.store(value, segment, offset)?;
+        // Keep the decoded program in step with self-modifying code
+        if segment == 0 {
+            let index = offset as usize;
+            self.program[index] = rumdis::decode(value);
+            if let Some(fused) = &mut self.fused {
+                fuse::refuse(fused, &self.program, index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute instructions until the machine halts.
+    ///
+    /// An I/O device that reports `WouldBlock` is polled until input arrives;
+    /// use `run_for` to regain control instead.
+    ///
+    /// # Errors
+    /// The `Fault` that stopped the machine, if it did not halt normally
+    pub fn run(&mut self) -> Result<(), Fault> {
+        if self.fused.is_some() {
+            return self.run_fused(None).map(|_| ());
+        }
+        while self.step()? != Status::Halted {}
+        Ok(())
+    }
+
+    /// Execute at most `budget` instructions.
+    ///
+    /// # Returns
+    /// Whether the machine halted, used up its budget, or is blocked waiting for input
+    ///
+    /// # Errors
+    /// The `Fault` that stopped the machine, if an instruction failed
+    pub fn run_for(&mut self, budget: u64) -> Result<Outcome, Fault> {
+        if self.fused.is_some() {
+            return self.run_fused(Some(budget));
+        }
+        self.run_observed(Some(budget), &mut ())
+    }
+
+    /// Execute a single instruction, reporting it to `observer`.
+    ///
+    /// # Errors
+    /// A `Fault` if the instruction fails, reported to `observer.fault`
+    /// instead of `observer.after`
+    pub fn step_observed<O: Observer>(&mut self, observer: &mut O) -> Result<Status, Fault> {
+        if self.halted {
+            return Ok(Status::Halted);
+        }
+        observer.before(self);
+        let status = self
+            .step()
+            .inspect_err(|fault| observer.fault(self, fault))?;
+        if status != Status::WaitingForInput {
+            observer.after(self);
+        }
+        Ok(status)
+    }
+
+    /// Execute at most `budget` instructions (or until the machine halts if
+    /// there is no budget), reporting each one to `observer`.
+    ///
+    /// Without a budget, an I/O device that reports `WouldBlock` is polled
+    /// until input arrives, as in `run`.
+    ///
+    /// # Returns
+    /// Whether the machine halted, used up its budget, or is blocked waiting for input
+    ///
+    /// # Errors
+    /// The `Fault` that stopped the machine, if an instruction failed
+    pub fn run_observed<O: Observer>(
+        &mut self,
+        budget: Option<u64>,
+        observer: &mut O,
+    ) -> Result<Outcome, Fault> {
+        let mut remaining = budget;
+        while remaining != Some(0) {
+            match self.step_observed(observer)? {
+                Status::Running => {}
+                Status::Halted => return Ok(Outcome::Halted),
+                Status::WaitingForInput if budget.is_some() => return Ok(Outcome::WaitingForInput),
+                Status::WaitingForInput => continue,
             }
-            _ => panic!("Invalid opcode: {}", opcode),
+            remaining = remaining.map(|n| n - 1);
+        }
+        if self.halted {
+            Ok(Outcome::Halted)
+        } else {
+            Ok(Outcome::Yielded)
         }
+    }
+}
+
+/// Decode every word of a program for execution.
+fn decode_program(program: &[u32]) -> Vec<rumdis::Decoded> {
+    program.iter().map(|&word| rumdis::decode(word)).collect()
+}
+
+/// Watches the instructions executed by a `Machine`, e.g. to trace or profile a run.
+pub trait Observer {
+    /// Called before each instruction, while the program counter still points at it.
+    fn before<D: IoDevice, M: SegmentStore>(&mut self, _machine: &Machine<D, M>) {}
+
+    /// Called after each instruction that completes. Not called for an
+    /// instruction that faults or is waiting for input.
+    fn after<D: IoDevice, M: SegmentStore>(&mut self, _machine: &Machine<D, M>) {}
+
+    /// Called instead of `after` for an instruction that faults, with the
+    /// program counter still pointing at it.
+    fn fault<D: IoDevice, M: SegmentStore>(&mut self, _machine: &Machine<D, M>, _fault: &Fault) {}
+}
+
+/// An observer that does nothing.
+impl Observer for () {}
+
+/// Two observers watching the same run.
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn before<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        self.0.before(machine);
+        self.1.before(machine);
+    }
+
+    fn after<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        self.0.after(machine);
+        self.1.after(machine);
+    }
+
+    fn fault<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>, fault: &Fault) {
+        self.0.fault(machine, fault);
+        self.1.fault(machine, fault);
+    }
+}
+
+impl<O: Observer> Observer for Option<O> {
+    fn before<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        if let Some(observer) = self {
+            observer.before(machine);
+        }
+    }
+
+    fn after<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        if let Some(observer) = self {
+            observer.after(machine);
+        }
+    }
+
+    fn fault<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>, fault: &Fault) {
+        if let Some(observer) = self {
+            observer.fault(machine, fault);
+        }
+    }
+}
+
+/// Execute the program loaded into segment 0 of `memory` until it halts.
+///
+/// # Errors
+/// The `Fault` that stopped the machine, if it did not halt normally
+pub fn execute<M: SegmentStore>(memory: M) -> Result<(), Fault> {
+    Machine::new(memory).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rumio::Slow;
+
+    // loadv r1, 7; loadv r2, 5; add r3, r1, r2; halt
+    const PROGRAM: [u32; 4] = [0xD200_0007, 0xD400_0005, 0x3000_00CA, 0x7000_0000];
+
+    fn machine(program: &[u32]) -> Machine<Streams<&'static [u8], Vec<u8>>> {
+        Machine::from_program(program.to_vec(), &b""[..], Vec::new())
+    }
+
+    #[test]
+    fn step_one_at_a_time() {
+        let mut machine = machine(&PROGRAM);
+        assert_eq!(machine.step(), Ok(Status::Running));
+        assert_eq!(machine.registers()[1], 7);
+        assert_eq!(machine.program_counter(), 1);
+        assert_eq!(machine.step(), Ok(Status::Running));
+        assert_eq!(machine.step(), Ok(Status::Running));
+        assert_eq!(machine.registers()[3], 12);
+        assert_eq!(machine.step(), Ok(Status::Halted));
+        assert_eq!(machine.program_counter(), 3);
+        assert_eq!(machine.step(), Ok(Status::Halted));
+    }
+
+    #[test]
+    fn run_off_end_faults() {
+        let mut machine = machine(&PROGRAM[..3]);
+        let fault = machine.run().unwrap_err();
+        assert_eq!(fault.error, UmError::PcOutOfRange { length: 3 });
+        assert_eq!(fault.program_counter, 3);
+        assert_eq!(fault.instruction, None);
+        assert_eq!(machine.registers()[3], 12);
+    }
+
+    #[test]
+    fn divide_by_zero_faults() {
+        // loadv r1, 7; div r2, r1, r0
+        let mut machine = machine(&[0xD200_0007, 0x5000_0088]);
+        let fault = machine.run().unwrap_err();
+        assert_eq!(fault.error, UmError::DivideByZero);
+        assert_eq!(fault.program_counter, 1);
+        assert_eq!(fault.instruction, Some(0x5000_0088));
+        assert_eq!(
+            fault.to_string(),
+            "machine fault at pc 1: division by zero (instruction 0x50000088: div r2, r1, r0)"
+        );
+    }
+
+    #[test]
+    fn invalid_opcode_faults() {
+        let mut machine = machine(&[0xE000_0000]);
+        assert_eq!(
+            machine.step().unwrap_err().error,
+            UmError::InvalidOpcode(14)
+        );
+        assert!(!machine.is_halted());
+    }
+
+    #[test]
+    fn unmapped_segment_faults() {
+        // loadv r1, 1; load r2, r1, r0
+        let mut machine = machine(&[0xD200_0001, 0x1000_0088]);
+        assert_eq!(
+            machine.run().unwrap_err().error,
+            UmError::UnmappedSegment(1)
+        );
+    }
+
+    #[test]
+    fn echo_through_buffers() {
+        // 0: input r1
+        // 1: output r1
+        // 2: input r1
+        // 3: output r1
+        // 4: halt
+        let program = vec![
+            0xB000_0001,
+            0xA000_0001,
+            0xB000_0001,
+            0xA000_0001,
+            0x7000_0000,
+        ];
+        let mut machine = Machine::from_program(program, &b"ok"[..], Vec::new());
+        machine.run().unwrap();
+        assert_eq!(machine.into_io().output, b"ok");
+    }
+
+    #[test]
+    fn output_flushed_on_halt_and_fault() {
+        // loadv r1, 'A'; output r1; halt
+        let program = vec![0xD200_0041, 0xA000_0001, 0x7000_0000];
+        let output = std::io::BufWriter::new(Vec::new());
+        let mut halting = Machine::from_program(program, &b""[..], output);
+        assert_eq!(halting.run(), Ok(()));
+        assert_eq!(halting.io().output.get_ref(), b"A");
+
+        // loadv r1, 'A'; output r1; div r1, r1, r0
+        let program = vec![0xD200_0041, 0xA000_0001, 0x5000_0048];
+        let output = std::io::BufWriter::new(Vec::new());
+        let mut faulting = Machine::from_program(program, &b""[..], output);
+        assert!(faulting.run().is_err());
+        assert_eq!(faulting.io().output.get_ref(), b"A");
+    }
+
+    // 0: input r1
+    // 1: input r2
+    // 2: input r3
+    // 3: halt
+    const READ_THREE: [u32; 4] = [0xB000_0001, 0xB000_0002, 0xB000_0003, 0x7000_0000];
+
+    #[test]
+    fn input_reads_bytes() {
+        let mut machine = Machine::from_program(READ_THREE.to_vec(), &b"\x00a\xff"[..], Vec::new());
+        assert_eq!(machine.run(), Ok(()));
+        assert_eq!(machine.registers()[1..4], [0, 0x61, 0xFF]);
+    }
+
+    #[test]
+    fn input_eof_loads_all_ones() {
+        let mut machine = Machine::from_program(READ_THREE.to_vec(), &b"z"[..], Vec::new());
+        assert_eq!(machine.run(), Ok(()));
+        assert_eq!(machine.registers()[1..4], [0x7A, 0xFFFF_FFFF, 0xFFFF_FFFF]);
+    }
+
+    #[test]
+    fn strict_eof_faults() {
+        let mut machine = Machine::from_program(READ_THREE.to_vec(), &b"z"[..], Vec::new());
+        machine.set_strict_eof(true);
+        let fault = machine.run().unwrap_err();
+        assert_eq!(fault.error, UmError::InputEof);
+        assert_eq!(fault.program_counter, 1);
+        assert_eq!(machine.registers()[1], 0x7A);
+    }
+
+    #[test]
+    fn run_for_yields_on_budget() {
+        // 0: loadv r1, 0
+        // 1: loadp r0, r1 (jump to 0, forever)
+        let mut looping = machine(&[0xD200_0000, 0xC000_0001]);
+        assert_eq!(looping.run_for(1001), Ok(Outcome::Yielded));
+        assert_eq!(looping.steps(), 1001);
+        assert_eq!(looping.program_counter(), 1);
+
+        let mut halting = machine(&PROGRAM);
+        assert_eq!(halting.run_for(3), Ok(Outcome::Yielded));
+        assert_eq!(halting.run_for(3), Ok(Outcome::Halted));
+        assert_eq!(halting.steps(), 4);
+        assert_eq!(halting.run_for(0), Ok(Outcome::Halted));
+    }
+
+    #[test]
+    fn run_for_waits_for_input() {
+        let mut memory = rummem::Memory::new();
+        // loadv r1, 1; input r1
+        memory.load_program(vec![0xD200_0001, 0xB000_0001]);
+        let mut machine = Machine::with_io(memory, Slow(u32::MAX));
+        assert_eq!(machine.run_for(10), Ok(Outcome::WaitingForInput));
+        assert_eq!(machine.program_counter(), 1);
+        assert_eq!(machine.steps(), 1);
+        assert_eq!(machine.registers()[1], 1);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // 0: loadv r1, 6
+        // 1: loadv r2, 7
+        // 2: loadv r3, 0x4000
+        // 3: mult r2, r2, r3
+        // 4: mult r2, r2, r3 (r2 is now a Halt instruction)
+        // 5: store r0, r1, r2
+        // 6: (invalid, overwritten by the store above)
+        let program = [
+            0xD200_0006,
+            0xD400_0007,
+            0xD600_4000,
+            0x4000_0093,
+            0x4000_0093,
+            0x2000_000A,
+            0xF000_0000,
+        ];
+        let mut machine = machine(&program);
+        assert_eq!(machine.run(), Ok(()));
+        assert_eq!(machine.program_counter(), 6);
+        assert_eq!(machine.steps(), 7);
+    }
 
-        program_counter += 1;
+    #[test]
+    fn load_program_replaces_decoded_program() {
+        // 0: loadv r1, 1
+        // 1: map r2, r1
+        // 2: loadv r3, 0x4000
+        // 3: loadv r4, 7
+        // 4: mult r4, r4, r3
+        // 5: mult r4, r4, r3 (r4 is now a Halt instruction)
+        // 6: store r2, r0, r4
+        // 7: loadp r2, r0
+        let program = [
+            0xD200_0001,
+            0x8000_0011,
+            0xD600_4000,
+            0xD800_0007,
+            0x4000_0123,
+            0x4000_0123,
+            0x2000_0084,
+            0xC000_0010,
+        ];
+        let mut machine = machine(&program);
+        assert_eq!(machine.run(), Ok(()));
+        assert_eq!(machine.program_counter(), 0);
+        assert_eq!(machine.memory().program(), &[0x7000_0000]);
     }
 }
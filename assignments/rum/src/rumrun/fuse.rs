@@ -0,0 +1,343 @@
+use super::{Machine, Outcome, Status};
+use crate::{
+    rumdis::{Decoded, Operation},
+    rumerr::{Fault, UmError},
+    rumio::IoDevice,
+    rummem::SegmentStore,
+};
+
+/// How a `Machine` executes instructions, chosen with `rum --engine <name>`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Engine {
+    /// Dispatch on one instruction at a time.
+    #[default]
+    Plain,
+    /// Fuse common sequences of instructions into superinstructions when the
+    /// program is loaded, and dispatch on those.
+    Fused,
+}
+
+impl Engine {
+    /// Get the engine with the given name (`plain` or `fused`).
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "plain" => Some(Engine::Plain),
+            "fused" => Some(Engine::Fused),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::Plain => "plain",
+            Engine::Fused => "fused",
+        }
+    }
+}
+
+/// The most instructions fused into one superinstruction.
+const MAX_FUSED: usize = 3;
+
+/// A superinstruction standing for the sequence of instructions starting at
+/// a program counter, whose operands are read from the decoded program.
+///
+/// A fault leaves the machine as the plain interpreter would: the
+/// instructions before the faulting one are counted, and the program counter
+/// points at it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(super) enum Fusion {
+    /// `loadv`, `loadv`, `add`
+    LoadValuesAdd,
+    /// `load` then `output` of the loaded register
+    LoadOutput,
+    /// `nand t, b, c` then `nand a, t, t`, leaving `b & c` in register `a`
+    And,
+    /// `nand a, b, b`, leaving `!b` in register `a`
+    Not,
+}
+
+impl Fusion {
+    /// Get the number of instructions this stands for.
+    fn len(self) -> u64 {
+        match self {
+            Fusion::Not => 1,
+            Fusion::LoadOutput | Fusion::And => 2,
+            Fusion::LoadValuesAdd => 3,
+        }
+    }
+}
+
+/// Fuse a decoded program, giving the superinstruction (if any) at each program counter.
+pub(super) fn fuse(program: &[Decoded]) -> Vec<Option<Fusion>> {
+    (0..program.len()).map(|pc| fuse_at(program, pc)).collect()
+}
+
+/// Fuse again the superinstructions that include `index`, after it has been overwritten.
+pub(super) fn refuse(fused: &mut [Option<Fusion>], program: &[Decoded], index: usize) {
+    let start = index.saturating_sub(MAX_FUSED - 1);
+    for (pc, entry) in fused[start..=index].iter_mut().enumerate() {
+        *entry = fuse_at(program, start + pc);
+    }
+}
+
+/// Find the longest superinstruction starting at `pc`.
+fn fuse_at(program: &[Decoded], pc: usize) -> Option<Fusion> {
+    let operation = |i: usize| program.get(pc + i).and_then(|decoded| decoded.operation);
+    let first = program[pc];
+
+    match (operation(0), operation(1), operation(2)) {
+        (Some(Operation::LoadValue), Some(Operation::LoadValue), Some(Operation::Add)) => {
+            Some(Fusion::LoadValuesAdd)
+        }
+        (Some(Operation::LoadSegment), Some(Operation::Output), _)
+            if program[pc + 1].c == first.a =>
+        {
+            Some(Fusion::LoadOutput)
+        }
+        (Some(Operation::Nand), Some(Operation::Nand), _)
+            if program[pc + 1].b == first.a && program[pc + 1].c == first.a =>
+        {
+            Some(Fusion::And)
+        }
+        (Some(Operation::Nand), _, _) if first.b == first.c => Some(Fusion::Not),
+        _ => None,
+    }
+}
+
+impl<D: IoDevice, M: SegmentStore> Machine<D, M> {
+    /// Execute instructions as `run_observed` does without an observer, but
+    /// dispatching on superinstructions where there are any. A
+    /// superinstruction that would go past the budget is executed one
+    /// instruction at a time instead.
+    pub(super) fn run_fused(&mut self, budget: Option<u64>) -> Result<Outcome, Fault> {
+        let limit = budget.map_or(u64::MAX, |budget| self.steps.saturating_add(budget));
+        loop {
+            if self.halted {
+                return Ok(Outcome::Halted);
+            }
+            if self.steps == limit {
+                return Ok(Outcome::Yielded);
+            }
+
+            let pc = self.program_counter;
+            let (result, length) = match self.fused.as_ref().and_then(|fused| fused.get(pc)) {
+                Some(&Some(fusion)) if fusion.len() <= limit - self.steps => {
+                    (self.execute_fused(fusion), fusion.len())
+                }
+                Some(_) => (self.execute(self.program[pc]), 1),
+                None => {
+                    let length = self.memory.get_program_length();
+                    (Err(UmError::PcOutOfRange { length }), 1)
+                }
+            };
+            let status = self.complete(result, length)?;
+            match status {
+                Status::Running => {}
+                Status::Halted => return Ok(Outcome::Halted),
+                Status::WaitingForInput if budget.is_some() => return Ok(Outcome::WaitingForInput),
+                Status::WaitingForInput => {}
+            }
+        }
+    }
+
+    /// Execute the superinstruction at the current program counter. Only the
+    /// `load` of `LoadOutput` can fault, before anything has changed.
+    fn execute_fused(&mut self, fusion: Fusion) -> Result<Status, UmError> {
+        let pc = self.program_counter;
+        let first = self.program[pc];
+        let registers = &mut self.registers;
+        let r = |register: u8| register as usize;
+
+        match fusion {
+            Fusion::LoadValuesAdd => {
+                let (second, add) = (self.program[pc + 1], self.program[pc + 2]);
+                registers[r(first.a)] = first.value;
+                registers[r(second.a)] = second.value;
+                registers[r(add.a)] = u32::wrapping_add(registers[r(add.b)], registers[r(add.c)]);
+            }
+            Fusion::LoadOutput => {
+                let value = self
+                    .memory
+                    .load(registers[r(first.b)], registers[r(first.c)])?;
+                registers[r(first.a)] = value;
+                // A closed output device does not stop the machine. The
+                // Output is the second instruction of the pair.
+                let _ = self.io.output_at(self.steps + 1, value as u8);
+            }
+            Fusion::And => {
+                registers[r(first.a)] = !(registers[r(first.b)] & registers[r(first.c)]);
+                let second = self.program[pc + 1];
+                registers[r(second.a)] = !registers[r(first.a)];
+            }
+            Fusion::Not => {
+                registers[r(first.a)] = !registers[r(first.b)];
+            }
+        }
+        self.program_counter += fusion.len() as usize;
+        Ok(Status::Running)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{rumdis::decode, rumgen, rumio::Streams, rummem::FreeListMemory};
+
+    // 0: loadv r1, 'h'
+    // 1: loadv r2, 1
+    // 2: add r3, r1, r2
+    // 3: map r4, r2
+    // 4: store r4, r0, r3
+    // 5: load r5, r4, r0
+    // 6: output r5
+    // 7: nand r6, r3, r1
+    // 8: nand r7, r6, r6
+    // 9: output r7
+    // 10: nand r6, r1, r1
+    // 11: halt
+    const IDIOMS: [u32; 12] = [
+        0xD200_0068,
+        0xD400_0001,
+        0x3000_00CA,
+        0x8000_0022,
+        0x2000_0103,
+        0x1000_0160,
+        0xA000_0005,
+        0x6000_0199,
+        0x6000_01F6,
+        0xA000_0007,
+        0x6000_0189,
+        0x7000_0000,
+    ];
+
+    type TestMachine = Machine<Streams<&'static [u8], Vec<u8>>, FreeListMemory>;
+
+    fn machine(program: &[u32], engine: Engine) -> TestMachine {
+        let mut memory = FreeListMemory::new();
+        memory.load_program(program.to_vec());
+        let mut machine = Machine::with_io(memory, Streams::new(&b"input"[..], Vec::new()));
+        machine.set_engine(engine);
+        machine
+    }
+
+    fn assert_same(plain: &TestMachine, fused: &TestMachine) {
+        assert_eq!(fused.registers(), plain.registers());
+        assert_eq!(fused.program_counter(), plain.program_counter());
+        assert_eq!(fused.steps(), plain.steps());
+        assert_eq!(fused.io().output, plain.io().output);
+    }
+
+    #[test]
+    fn fuses_idioms() {
+        let program: Vec<Decoded> = IDIOMS.iter().map(|&word| decode(word)).collect();
+        let (add, load, and, not) = (
+            Some(Fusion::LoadValuesAdd),
+            Some(Fusion::LoadOutput),
+            Some(Fusion::And),
+            Some(Fusion::Not),
+        );
+        // The second nand of an AND is a NOT if it is reached on its own
+        assert_eq!(
+            fuse(&program),
+            [add, None, None, None, None, load, None, and, not, None, not, None]
+        );
+    }
+
+    #[test]
+    fn matches_plain_engine() {
+        let mut plain = machine(&IDIOMS, Engine::Plain);
+        let mut fused = machine(&IDIOMS, Engine::Fused);
+        assert_eq!(plain.run(), Ok(()));
+        assert_eq!(fused.run(), Ok(()));
+        assert_same(&plain, &fused);
+        assert_eq!(fused.io().output, b"ih");
+
+        for seed in 0..100 {
+            let program = rumgen::program(&mut StdRng::seed_from_u64(seed), 200);
+            let mut plain = machine(&program, Engine::Plain);
+            let mut fused = machine(&program, Engine::Fused);
+            assert_eq!(fused.run(), plain.run());
+            assert_same(&plain, &fused);
+        }
+    }
+
+    #[test]
+    fn budget_splits_superinstructions() {
+        let mut plain = machine(&IDIOMS, Engine::Plain);
+        let mut fused = machine(&IDIOMS, Engine::Fused);
+        for _ in 0..IDIOMS.len() {
+            assert_eq!(fused.run_for(2), plain.run_for(2));
+            assert_same(&plain, &fused);
+        }
+    }
+
+    #[test]
+    fn faults_like_plain_engine() {
+        // loadv r1, 5; loadv r2, 0; add r3, r1, r2;
+        // load r4, r0, r3 (past the end of segment 0); halt
+        let program = [
+            0xD200_0005,
+            0xD400_0000,
+            0x3000_00CA,
+            0x1000_0103,
+            0x7000_0000,
+        ];
+        let mut plain = machine(&program, Engine::Plain);
+        let mut fused = machine(&program, Engine::Fused);
+        assert_eq!(
+            fused.fused.as_ref().unwrap()[0],
+            Some(Fusion::LoadValuesAdd)
+        );
+        let fault = fused.run().unwrap_err();
+        assert_eq!(plain.run(), Err(fault.clone()));
+        assert_eq!(fault.program_counter, 3);
+        assert_same(&plain, &fused);
+
+        // load r4, r0, r3 (past the end of segment 0); output r4
+        let load_output = [0xD600_0005, 0x1000_0103, 0xA000_0004];
+        let mut plain = machine(&load_output, Engine::Plain);
+        let mut fused = machine(&load_output, Engine::Fused);
+        assert_eq!(fused.fused.as_ref().unwrap()[1], Some(Fusion::LoadOutput));
+        let fault = fused.run().unwrap_err();
+        assert_eq!(plain.run(), Err(fault.clone()));
+        assert_eq!(fault.program_counter, 1);
+        assert_same(&plain, &fused);
+
+        // Running off the end of segment 0 after a superinstruction
+        let mut fused = machine(&program[..3], Engine::Fused);
+        let fault = fused.run().unwrap_err();
+        assert_eq!(fault.error, UmError::PcOutOfRange { length: 3 });
+        assert_eq!(fault.program_counter, 3);
+        assert_eq!(fused.steps(), 3);
+    }
+
+    #[test]
+    fn follows_self_modifying_code() {
+        // 0: loadv r2, 4
+        // 1: store r0, r2, r0 (overwrites 4 with 0, a cmov that does nothing)
+        // 2: loadv r1, 'A'
+        // 3: loadv r3, 1
+        // 4: add r1, r1, r3
+        // 5: output r1
+        // 6: halt
+        let program = [
+            0xD400_0004,
+            0x2000_0010,
+            0xD200_0041,
+            0xD600_0001,
+            0x3000_004B,
+            0xA000_0001,
+            0x7000_0000,
+        ];
+        let mut fused = machine(&program, Engine::Fused);
+        assert_eq!(
+            fused.fused.as_ref().unwrap()[2],
+            Some(Fusion::LoadValuesAdd)
+        );
+        assert_eq!(fused.run(), Ok(()));
+        assert_eq!(fused.io().output, b"A");
+        assert_eq!(fused.steps(), 7);
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    collections::HashSet,
+    io::{self, ErrorKind, Read, Write},
+};
+
+use crate::{
+    rumio::IoDevice,
+    rummem::{Backend, SegmentStore},
+    rumrun::{Machine, Registers},
+};
+
+/// Magic bytes at the start of a snapshot.
+const MAGIC: &[u8; 4] = b"UMSS";
+/// Version of the snapshot format.
+const VERSION: u8 = 1;
+
+/// The complete state of a machine, as read back from a snapshot.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Snapshot {
+    /// The memory backend the machine was using.
+    pub backend: Backend,
+    pub registers: Registers,
+    pub program_counter: usize,
+    pub steps: u64,
+    pub halted: bool,
+    /// Every mapped segment, including segment 0, in increasing order of ID.
+    pub segments: Vec<(u32, Vec<u32>)>,
+    /// Unmapped IDs in the order the backend will reuse them.
+    pub free_ids: Vec<u32>,
+}
+
+impl Snapshot {
+    /// Take a snapshot of `machine`, e.g. to return to later with `restore_into`.
+    pub fn capture<D: IoDevice, M: SegmentStore>(machine: &Machine<D, M>) -> Snapshot {
+        let memory = machine.memory();
+        Snapshot {
+            backend: M::BACKEND,
+            registers: *machine.registers(),
+            program_counter: machine.program_counter(),
+            steps: machine.steps(),
+            halted: machine.is_halted(),
+            segments: memory
+                .mapped_ids()
+                .into_iter()
+                .map(|id| (id, memory.segment(id).unwrap_or(&[]).to_vec()))
+                .collect(),
+            free_ids: memory.free_ids(),
+        }
+    }
+
+    /// Return `machine` to the state in this snapshot, keeping its I/O device,
+    /// settings and memory limits.
+    pub fn restore_into<D: IoDevice, M: SegmentStore>(&self, machine: &mut Machine<D, M>) {
+        machine.set_state(
+            self.segments.clone(),
+            self.free_ids.clone(),
+            self.registers,
+            self.program_counter,
+            self.steps,
+            self.halted,
+        );
+    }
+
+    /// Rebuild the machine this snapshot was taken from.
+    ///
+    /// # Arguments
+    /// - `memory`: empty memory to restore into, normally of the backend in `self.backend`
+    /// - `io`: the I/O device for the resumed machine
+    pub fn restore<D: IoDevice, M: SegmentStore>(self, mut memory: M, io: D) -> Machine<D, M> {
+        memory.restore(self.segments, self.free_ids);
+        Machine::from_state(
+            memory,
+            io,
+            self.registers,
+            self.program_counter,
+            self.steps,
+            self.halted,
+        )
+    }
+}
+
+/// Write the complete state of `machine` as a snapshot.
+///
+/// The format is the header `UMSS` and a version byte, then a backend byte
+/// (0 for free-list, 1 for B-tree), the eight registers, the program counter,
+/// the step count (8 bytes) and a halted byte. Next come the number of mapped
+/// segments followed by each one's ID, length and words, and finally the
+/// number of free IDs followed by the IDs. All values are big-endian and,
+/// unless noted, 4 bytes long.
+pub fn write<D: IoDevice, M: SegmentStore>(
+    machine: &Machine<D, M>,
+    mut out: impl Write,
+) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION, backend_byte(M::BACKEND)])?;
+    for register in machine.registers() {
+        out.write_all(&register.to_be_bytes())?;
+    }
+    out.write_all(&(machine.program_counter() as u32).to_be_bytes())?;
+    out.write_all(&machine.steps().to_be_bytes())?;
+    out.write_all(&[machine.is_halted() as u8])?;
+
+    let memory = machine.memory();
+    let ids = memory.mapped_ids();
+    out.write_all(&(ids.len() as u32).to_be_bytes())?;
+    for id in ids {
+        let segment = memory.segment(id).unwrap_or(&[]);
+        out.write_all(&id.to_be_bytes())?;
+        out.write_all(&(segment.len() as u32).to_be_bytes())?;
+        for word in segment {
+            out.write_all(&word.to_be_bytes())?;
+        }
+    }
+
+    let free_ids = memory.free_ids();
+    out.write_all(&(free_ids.len() as u32).to_be_bytes())?;
+    for id in free_ids {
+        out.write_all(&id.to_be_bytes())?;
+    }
+    out.flush()
+}
+
+/// Read a snapshot written by `write`.
+///
+/// # Errors
+/// - `InvalidData` if the header is wrong or the snapshot is inconsistent
+///   (e.g. segment 0 is missing, an ID is both mapped and free, or a
+///   free-list ID is beyond the number of IDs the snapshot accounts for)
+/// - Any error from `input`, including `UnexpectedEof` for a truncated snapshot
+pub fn read(mut input: impl Read) -> io::Result<Snapshot> {
+    let mut header = [0_u8; 6];
+    input.read_exact(&mut header)?;
+    if &header[..4] != MAGIC || header[4] != VERSION {
+        return Err(invalid("not a version 1 UM snapshot"));
+    }
+    let backend = match header[5] {
+        0 => Backend::FreeList,
+        1 => Backend::BTree,
+        _ => return Err(invalid("unknown memory backend")),
+    };
+
+    let mut registers = [0; 8];
+    for register in registers.iter_mut() {
+        *register = read_u32(&mut input)?;
+    }
+    let program_counter = read_u32(&mut input)? as usize;
+    let mut steps = [0_u8; 8];
+    input.read_exact(&mut steps)?;
+    let mut halted = [0_u8; 1];
+    input.read_exact(&mut halted)?;
+
+    let count = read_u32(&mut input)?;
+    let mut segments: Vec<(u32, Vec<u32>)> = vec![];
+    for _ in 0..count {
+        let id = read_u32(&mut input)?;
+        if segments.last().is_some_and(|&(last, _)| last >= id) {
+            return Err(invalid("segment IDs are not in increasing order"));
+        }
+        let length = read_u32(&mut input)? as u64;
+        segments.push((id, read_words(&mut input, length)?));
+    }
+    if segments.first().map(|&(id, _)| id) != Some(0) {
+        return Err(invalid("segment 0 is missing"));
+    }
+
+    let count = read_u32(&mut input)?;
+    let mut free_ids = vec![];
+    let mut seen = HashSet::new();
+    for _ in 0..count {
+        let id = read_u32(&mut input)?;
+        if id == 0 || segments.binary_search_by_key(&id, |&(id, _)| id).is_ok() {
+            return Err(invalid("a free segment ID is mapped"));
+        }
+        if !seen.insert(id) {
+            return Err(invalid("a free segment ID is repeated"));
+        }
+        free_ids.push(id);
+    }
+    // Free-list memory hands out IDs from 0 up, so every ID it has used is
+    // either mapped or free. This also bounds the table restoring allocates.
+    if backend == Backend::FreeList {
+        let count = (segments.len() + free_ids.len()) as u64;
+        let mut ids = segments
+            .iter()
+            .map(|&(id, _)| id)
+            .chain(free_ids.iter().copied());
+        if ids.any(|id| id as u64 >= count) {
+            return Err(invalid("a segment ID is out of range for free-list memory"));
+        }
+    }
+
+    Ok(Snapshot {
+        backend,
+        registers,
+        program_counter,
+        steps: u64::from_be_bytes(steps),
+        halted: halted[0] != 0,
+        segments,
+        free_ids,
+    })
+}
+
+fn backend_byte(backend: Backend) -> u8 {
+    match backend {
+        Backend::FreeList => 0,
+        Backend::BTree => 1,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buffer = [0_u8; 4];
+    input.read_exact(&mut buffer)?;
+    Ok(u32::from_be_bytes(buffer))
+}
+
+/// Read `length` big-endian words, without trusting `length` for allocation.
+fn read_words(input: &mut impl Read, length: u64) -> io::Result<Vec<u32>> {
+    let mut bytes = vec![];
+    input.take(length * 4).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length * 4 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        rumio::Streams,
+        rummem::{BTreeMemory, FreeListMemory},
+        rumrun::Outcome,
+    };
+
+    // 0: loadv r1, 3
+    // 1: map r2, r1
+    // 2: map r3, r1
+    // 3: map r4, r1
+    // 4: unmap r3
+    // 5: unmap r2
+    // 6: store r4, r0, r1
+    // 7: map r5, r1
+    // 8: halt
+    const PROGRAM: [u32; 9] = [
+        0xD200_0003,
+        0x8000_0011,
+        0x8000_0019,
+        0x8000_0021,
+        0x9000_0003,
+        0x9000_0002,
+        0x2000_0101,
+        0x8000_0029,
+        0x7000_0000,
+    ];
+
+    type TestMachine<M> = Machine<Streams<&'static [u8], Vec<u8>>, M>;
+
+    /// Run `PROGRAM` to completion twice: once straight through, and once
+    /// saved after six steps and resumed from the snapshot.
+    fn round_trip<M: SegmentStore>() -> (TestMachine<M>, TestMachine<M>) {
+        let mut memory = M::default();
+        memory.load_program(PROGRAM.to_vec());
+        let mut original = Machine::with_io(memory, Streams::new(&b""[..], Vec::new()));
+        assert_eq!(original.run_for(6), Ok(Outcome::Yielded));
+
+        let mut saved = vec![];
+        write(&original, &mut saved).unwrap();
+        let snapshot = read(&saved[..]).unwrap();
+        assert_eq!(snapshot.backend, M::BACKEND);
+        assert_eq!(snapshot.steps, 6);
+        let mut resumed = snapshot.restore(M::default(), Streams::new(&b""[..], Vec::new()));
+
+        assert_eq!(original.run(), Ok(()));
+        assert_eq!(resumed.run(), Ok(()));
+        assert_eq!(resumed.steps(), 9);
+        assert_eq!(resumed.registers()[..5], original.registers()[..5]);
+        let segment = resumed.registers()[4];
+        assert_eq!(resumed.memory().load(segment, 0), Ok(3));
+        assert_eq!(resumed.memory().live_segments(), 3);
+        (original, resumed)
+    }
+
+    #[test]
+    fn freelist_round_trip() {
+        // The free list is restored in order, so the last map reuses the same ID
+        let (original, resumed) = round_trip::<FreeListMemory>();
+        assert_eq!(resumed.registers(), original.registers());
+        assert_eq!(
+            resumed.memory().mapped_ids(),
+            original.memory().mapped_ids()
+        );
+    }
+
+    #[test]
+    fn btree_round_trip() {
+        round_trip::<BTreeMemory>();
+    }
+
+    #[test]
+    fn captures_in_memory() {
+        let mut memory = FreeListMemory::new();
+        memory.load_program(PROGRAM.to_vec());
+        let mut machine = Machine::with_io(memory, Streams::new(&b""[..], Vec::new()));
+        assert_eq!(machine.run_for(6), Ok(Outcome::Yielded));
+        let snapshot = Snapshot::capture(&machine);
+        let mut saved = vec![];
+        write(&machine, &mut saved).unwrap();
+        assert_eq!(read(&saved[..]).unwrap(), snapshot);
+
+        assert_eq!(machine.run(), Ok(()));
+        let finished = Snapshot::capture(&machine);
+        snapshot.restore_into(&mut machine);
+        assert_eq!(Snapshot::capture(&machine), snapshot);
+        assert_eq!(machine.run(), Ok(()));
+        assert_eq!(Snapshot::capture(&machine), finished);
+    }
+
+    #[test]
+    fn rejects_out_of_range_ids() {
+        let mut memory = FreeListMemory::new();
+        memory.load_program(PROGRAM.to_vec());
+        let mut machine = Machine::with_io(memory, Streams::new(&b""[..], Vec::new()));
+        assert_eq!(machine.run_for(6), Ok(Outcome::Yielded));
+        let mut saved = vec![];
+        write(&machine, &mut saved).unwrap();
+        assert!(read(&saved[..]).is_ok());
+
+        // After the header, registers, program counter, steps, halted byte
+        // and segment count, segment 0 is followed by segment 3; then IDs 1
+        // and 2 are free
+        let segment = 4 + 2 + 8 * 4 + 4 + 8 + 1 + 4 + 4 + 4 + PROGRAM.len() * 4;
+        assert_eq!(saved[segment..segment + 4], [0, 0, 0, 3]);
+        let end = saved.len();
+        for index in [segment, end - 4] {
+            let mut huge = saved.clone();
+            huge[index..index + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+            assert_eq!(read(&huge[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+
+        // Free IDs 1 and 1 would pass the range check, but map would hand
+        // out segment 1 twice
+        let mut repeated = saved.clone();
+        repeated[end - 4..].copy_from_slice(&1_u32.to_be_bytes());
+        assert_eq!(
+            read(&repeated[..]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn rejects_bad_snapshots() {
+        let machine = Machine::from_program(PROGRAM.to_vec(), &b""[..], Vec::new());
+        let mut saved = vec![];
+        write(&machine, &mut saved).unwrap();
+
+        let mut wrong_version = saved.clone();
+        wrong_version[4] = 2;
+        assert_eq!(
+            read(&wrong_version[..]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read(&saved[..saved.len() - 5]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+}
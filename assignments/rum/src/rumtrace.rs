@@ -0,0 +1,371 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    ops::RangeInclusive,
+};
+
+use crate::{
+    rumdis::{self, Operation},
+    rumerr::Fault,
+    rumio::IoDevice,
+    rummem::SegmentStore,
+    rumrun::{Machine, Observer, Registers},
+};
+
+/// Magic bytes at the start of a binary trace.
+const MAGIC: &[u8; 4] = b"UMTR";
+/// Version of the binary trace format.
+const VERSION: u8 = 1;
+
+/// How trace records are written.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    /// One line of text per instruction.
+    Text,
+    /// A compact binary encoding, read back with `read_binary`.
+    Binary,
+}
+
+/// One executed instruction.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Record {
+    /// Number of instructions executed before this one.
+    pub step: u64,
+    pub program_counter: usize,
+    pub word: u32,
+    pub before: Registers,
+    pub after: Registers,
+}
+
+/// Selects which instructions are traced. An empty filter traces everything.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    /// Only trace instructions at these program counter values.
+    pub pcs: Option<RangeInclusive<usize>>,
+    /// Only trace these operations.
+    pub operations: Option<Vec<Operation>>,
+}
+
+impl Filter {
+    pub fn matches(&self, program_counter: usize, word: u32) -> bool {
+        if let Some(pcs) = &self.pcs {
+            if !pcs.contains(&program_counter) {
+                return false;
+            }
+        }
+        match &self.operations {
+            Some(operations) => rumdis::disassemble(&word)
+                .operation()
+                .is_some_and(|op| operations.contains(&op)),
+            None => true,
+        }
+    }
+}
+
+/// Parse a program counter range such as `100-200` (inclusive) or a single value such as `42`.
+pub fn parse_pc_range(text: &str) -> Option<RangeInclusive<usize>> {
+    match text.split_once('-') {
+        Some((start, end)) => Some(start.trim().parse().ok()?..=end.trim().parse().ok()?),
+        None => {
+            let pc = text.trim().parse().ok()?;
+            Some(pc..=pc)
+        }
+    }
+}
+
+/// Parse a comma-separated list of mnemonics such as `loadp,map,unmap`.
+pub fn parse_operations(text: &str) -> Option<Vec<Operation>> {
+    text.split(',')
+        .map(|mnemonic| Operation::from_mnemonic(mnemonic.trim()))
+        .collect()
+}
+
+/// Writes trace records for the instructions selected by a `Filter`.
+///
+/// Write errors are remembered rather than interrupting the machine, and are
+/// reported by `finish`.
+pub struct Tracer<W: Write> {
+    out: W,
+    format: Format,
+    filter: Filter,
+    error: Option<io::Error>,
+    started: bool,
+    /// The instruction about to execute, if it passed the filter.
+    pending: Option<(u64, usize, u32, Registers)>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, format: Format, filter: Filter) -> Tracer<W> {
+        Tracer {
+            out,
+            format,
+            filter,
+            error: None,
+            started: false,
+            pending: None,
+        }
+    }
+
+    /// Write a single record, regardless of the filter.
+    pub fn record(&mut self, record: &Record) {
+        if self.error.is_none() {
+            if let Err(e) = self.write(record) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self.format {
+            Format::Text => write_text(&mut self.out, record),
+            Format::Binary => {
+                if !self.started {
+                    self.out.write_all(MAGIC)?;
+                    self.out.write_all(&[VERSION])?;
+                    self.started = true;
+                }
+                write_binary(&mut self.out, record)
+            }
+        }
+    }
+
+    /// Flush the trace and return its destination.
+    ///
+    /// # Errors
+    /// The first error encountered while writing the trace
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Observer for Tracer<W> {
+    fn before<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        let program_counter = machine.program_counter();
+        self.pending = machine
+            .current_word()
+            .filter(|&word| self.filter.matches(program_counter, word))
+            .map(|word| (machine.steps(), program_counter, word, *machine.registers()));
+    }
+
+    fn after<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>) {
+        if let Some((step, program_counter, word, before)) = self.pending.take() {
+            self.record(&Record {
+                step,
+                program_counter,
+                word,
+                before,
+                after: *machine.registers(),
+            });
+        }
+    }
+
+    /// Trace the faulting instruction too, as the last record.
+    fn fault<D: IoDevice, M: SegmentStore>(&mut self, machine: &Machine<D, M>, _fault: &Fault) {
+        self.after(machine);
+    }
+}
+
+/// Format registers as eight space-separated hex words.
+fn show_registers(registers: &Registers) -> String {
+    registers
+        .iter()
+        .map(|r| format!("{:08x}", r))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Write a record as `step [pc] word instruction | before | after`.
+fn write_text(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} [{}] {:08x} {:<22} | {} | {}",
+        record.step,
+        record.program_counter,
+        record.word,
+        rumdis::disassemble(&record.word).to_string(),
+        show_registers(&record.before),
+        show_registers(&record.after)
+    )
+}
+
+/// Write a record in the binary format: the step (8 bytes), program counter
+/// and instruction word (4 bytes each), the registers before execution
+/// (32 bytes), then a byte with one bit set per register that changed,
+/// followed by the new value of each changed register. All values are big-endian.
+fn write_binary(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    out.write_all(&record.step.to_be_bytes())?;
+    out.write_all(&(record.program_counter as u32).to_be_bytes())?;
+    out.write_all(&record.word.to_be_bytes())?;
+    for register in record.before {
+        out.write_all(&register.to_be_bytes())?;
+    }
+
+    let mut changed = 0_u8;
+    for i in 0..8 {
+        if record.before[i] != record.after[i] {
+            changed |= 1 << i;
+        }
+    }
+    out.write_all(&[changed])?;
+    for i in 0..8 {
+        if changed & (1 << i) != 0 {
+            out.write_all(&record.after[i].to_be_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buffer = [0_u8; 4];
+    input.read_exact(&mut buffer)?;
+    Ok(u32::from_be_bytes(buffer))
+}
+
+/// Read back a trace written in the binary format.
+///
+/// # Errors
+/// - `InvalidData` if the trace does not start with the expected header
+/// - Any error from `input`, including `UnexpectedEof` for a truncated record
+pub fn read_binary(mut input: impl Read) -> io::Result<Vec<Record>> {
+    let mut header = [0_u8; 5];
+    match input.read_exact(&mut header) {
+        Ok(()) => {}
+        // A trace in which nothing was selected is empty
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(vec![]),
+        Err(e) => return Err(e),
+    }
+    if &header[..4] != MAGIC || header[4] != VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a version 1 UM binary trace",
+        ));
+    }
+
+    let mut records = vec![];
+    loop {
+        // The trace may only end between records
+        let mut step = [0_u8; 8];
+        match input.read_exact(&mut step[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e),
+        }
+        input.read_exact(&mut step[1..])?;
+        let program_counter = read_u32(&mut input)? as usize;
+        let word = read_u32(&mut input)?;
+        let mut before = [0; 8];
+        for register in before.iter_mut() {
+            *register = read_u32(&mut input)?;
+        }
+
+        let mut changed = [0_u8; 1];
+        input.read_exact(&mut changed)?;
+        let mut after = before;
+        for (i, register) in after.iter_mut().enumerate() {
+            if changed[0] & (1 << i) != 0 {
+                *register = read_u32(&mut input)?;
+            }
+        }
+
+        records.push(Record {
+            step: u64::from_be_bytes(step),
+            program_counter,
+            word,
+            before,
+            after,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rumrun::Outcome;
+
+    // 0: loadv r1, 7
+    // 1: loadv r2, 5
+    // 2: add r3, r1, r2
+    // 3: halt
+    const PROGRAM: [u32; 4] = [0xD200_0007, 0xD400_0005, 0x3000_00CA, 0x7000_0000];
+
+    fn trace(format: Format, filter: Filter) -> Vec<u8> {
+        let mut machine = Machine::from_program(PROGRAM.to_vec(), &b""[..], Vec::new());
+        let mut tracer = Tracer::new(Vec::new(), format, filter);
+        assert_eq!(machine.run_observed(None, &mut tracer), Ok(Outcome::Halted));
+        tracer.finish().unwrap()
+    }
+
+    #[test]
+    fn text_trace() {
+        let text = String::from_utf8(trace(Format::Text, Filter::default())).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].starts_with("2 [2] 300000ca add r3, r1, r2"));
+        assert!(lines[2].ends_with(
+            "| 00000000 00000007 00000005 00000000 00000000 00000000 00000000 00000000 \
+             | 00000000 00000007 00000005 0000000c 00000000 00000000 00000000 00000000"
+        ));
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let records = read_binary(&trace(Format::Binary, Filter::default())[..]).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[2].word, 0x3000_00CA);
+        assert_eq!(records[2].before[3], 0);
+        assert_eq!(records[2].after, [0, 7, 5, 12, 0, 0, 0, 0]);
+        assert_eq!(records[3].step, 3);
+    }
+
+    #[test]
+    fn traces_faulting_instruction() {
+        // loadv r1, 1; unmap r1
+        let mut machine =
+            Machine::from_program(vec![0xD200_0001, 0x9000_0001], &b""[..], Vec::new());
+        let mut tracer = Tracer::new(Vec::new(), Format::Binary, Filter::default());
+        assert!(machine.run_observed(None, &mut tracer).is_err());
+        let trace = tracer.finish().unwrap();
+        let records = read_binary(&trace[..]).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].program_counter, 1);
+        assert_eq!(records[1].word, 0x9000_0001);
+        assert_eq!(records[1].after, records[1].before);
+    }
+
+    #[test]
+    fn rejects_partial_records() {
+        let trace = trace(Format::Binary, Filter::default());
+        for length in [trace.len() - 1, 5 + 3] {
+            assert_eq!(
+                read_binary(&trace[..length]).unwrap_err().kind(),
+                ErrorKind::UnexpectedEof
+            );
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let by_pc = Filter {
+            pcs: parse_pc_range("1-2"),
+            operations: None,
+        };
+        let records = read_binary(&trace(Format::Binary, by_pc)[..]).unwrap();
+        let pcs: Vec<usize> = records.iter().map(|r| r.program_counter).collect();
+        assert_eq!(pcs, vec![1, 2]);
+
+        let by_op = Filter {
+            pcs: None,
+            operations: parse_operations("add,halt"),
+        };
+        let records = read_binary(&trace(Format::Binary, by_op)[..]).unwrap();
+        let pcs: Vec<usize> = records.iter().map(|r| r.program_counter).collect();
+        assert_eq!(pcs, vec![2, 3]);
+
+        assert_eq!(parse_pc_range("42"), Some(42..=42));
+        assert_eq!(parse_pc_range("x-1"), None);
+        assert_eq!(parse_operations("add,jump"), None);
+    }
+}
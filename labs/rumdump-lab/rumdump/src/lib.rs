@@ -1,2 +1 @@
 pub mod rumdis;
-pub mod rumload;
\ No newline at end of file
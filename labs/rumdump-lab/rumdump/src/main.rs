@@ -1,14 +1,74 @@
+use rum::{rumcov, rumload};
 use rumdump::rumdis;
-use rumdump::rumload;
-use std::env;
+use std::{env, fs::File, io::BufReader, process::exit};
 
 fn main() {
-    let input = env::args().nth(1);
-    let instructions = rumload::load(input.as_deref());
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // `--coverage <file>` annotates each word with its hits from `rum --coverage`
+    let coverage = match args.iter().position(|arg| arg == "--coverage") {
+        Some(index) if index + 1 < args.len() => {
+            let path = args.remove(index + 1);
+            args.remove(index);
+            let report = File::open(&path)
+                .and_then(|file| rumcov::Report::read_json(BufReader::new(file)))
+                .unwrap_or_else(|e| {
+                    eprintln!("could not read coverage {}: {}", path, e);
+                    exit(1);
+                });
+            Some(report)
+        }
+        Some(_) => {
+            eprintln!("Usage: rumdump [--coverage <file>] [program]");
+            exit(1);
+        }
+        None => None,
+    };
+
+    // Read the program from stdin if no file is given
+    let input = args.first().cloned().unwrap_or_else(|| String::from("-"));
+    let instructions = rumload::load_path(&input).map(|image| image.words).unwrap_or_else(|e| {
+        eprintln!("could not load {}: {}", input, e);
+        exit(1);
+    });
 
     println!("{} instructions", instructions.len());
 
-    for instruction in instructions {
-        println!("{}", rumdis::disassemble(instruction));
+    let report = match &coverage {
+        Some(report) => report,
+        None => {
+            for instruction in instructions {
+                println!("{}", rumdis::disassemble(instruction));
+            }
+            return;
+        }
+    };
+
+    let hits = report.hits(&instructions).unwrap_or_else(|| {
+        eprintln!(
+            "{} was not run (no coverage for program {})",
+            input,
+            rumcov::key(&instructions)
+        );
+        exit(1);
+    });
+    let executed = hits.iter().filter(|&&count| count > 0).count();
+    println!(
+        "{} executed ({:.2}%)",
+        executed,
+        100.0 * executed as f64 / instructions.len().max(1) as f64
+    );
+    // Words that were never executed are marked like gcov's `#####`
+    for (index, (instruction, count)) in instructions.iter().zip(hits).enumerate() {
+        let count = match count {
+            0 => String::from("#####"),
+            count => count.to_string(),
+        };
+        println!(
+            "{:>12} {:>8}: {}",
+            count,
+            index,
+            rumdis::disassemble(*instruction)
+        );
     }
 }